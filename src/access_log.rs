@@ -0,0 +1,502 @@
+use crate::client_ip::TrustedProxies;
+use crate::metrics::SiteLabel;
+use crate::request_id::RequestId;
+use crate::routes::INTERNAL_ROUTE_PREFIX;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::ServiceResponse;
+use log::{error, info};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Stdout, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const ACCESS_LOG_TARGET: &str = "traefik_pages::access";
+
+/// Most site logs kept open at once. The least recently used is closed to make room for another.
+const MAX_OPEN_SITE_LOGS: usize = 64;
+
+/// Lines waiting to be written. Any more are dropped, rather than holding up requests.
+const WRITE_QUEUE_SIZE: usize = 4096;
+
+/// The file a request was served from, attached to the request so it can be logged
+pub struct ServedFile(pub PathBuf);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogFormat {
+    /// Similar to the combined log format, through the standard logger
+    Text,
+    /// A JSON object per line, written to stdout
+    Json,
+}
+
+impl AccessLogFormat {
+    pub fn from_name(name: &str) -> Option<AccessLogFormat> {
+        match name.to_lowercase().as_str() {
+            "text" => Some(AccessLogFormat::Text),
+            "json" => Some(AccessLogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Which requests are logged
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessLogFilter {
+    pub log_internal: bool,
+    pub log_health_checks: bool,
+    /// Fraction of successful requests to log. Errors are always logged.
+    pub sample_rate: f64,
+}
+
+impl Default for AccessLogFilter {
+    fn default() -> Self {
+        AccessLogFilter {
+            log_internal: false,
+            log_health_checks: false,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+fn is_health_check(path: &str) -> bool {
+    path == format!("/{}/health", INTERNAL_ROUTE_PREFIX)
+        || path == format!("/{}/ready", INTERNAL_ROUTE_PREFIX)
+}
+
+fn is_internal(path: &str) -> bool {
+    path.trim_start_matches('/')
+        .starts_with(INTERNAL_ROUTE_PREFIX)
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct AccessLogEntry {
+    /// Seconds since the Unix epoch
+    pub time: f64,
+    pub client_ip: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub version: String,
+    pub status: u16,
+    pub bytes: u64,
+    /// Seconds taken to handle the request
+    pub duration: f64,
+    pub site: Option<String>,
+    pub file: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl AccessLogEntry {
+    pub fn new() -> Self {
+        AccessLogEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_response<B: MessageBody>(
+        response: &ServiceResponse<B>,
+        trusted_proxies: &TrustedProxies,
+        duration: Duration,
+    ) -> Self {
+        let request = response.request();
+        let get_header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let extensions = request.extensions();
+
+        AccessLogEntry {
+            client_ip: trusted_proxies
+                .client_ip(request.head())
+                .map(|ip| ip.to_string()),
+            method: request.method().to_string(),
+            path: request.path().to_owned(),
+            query: request.query_string().to_owned(),
+            version: format!("{:?}", request.version()),
+            status: response.status().as_u16(),
+            bytes: match response.response().body().size() {
+                BodySize::Sized(n) => n,
+                _ => 0,
+            },
+            duration: duration.as_secs_f64(),
+            site: extensions.get::<SiteLabel>().map(|label| label.0.clone()),
+            file: extensions
+                .get::<ServedFile>()
+                .map(|file| file.0.display().to_string()),
+            referer: get_header("Referer"),
+            user_agent: get_header("User-Agent"),
+            request_id: extensions.get::<RequestId>().map(|id| id.0.clone()),
+            ..AccessLogEntry::new()
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let query = if self.query.is_empty() {
+            String::new()
+        } else {
+            format!("?{}", self.query)
+        };
+        format!(
+            r#"{} "{} {}{} {}" {} {} "{}" "{}" {:.6} {}"#,
+            self.client_ip.as_deref().unwrap_or("-"),
+            self.method,
+            self.path,
+            query,
+            self.version,
+            self.status,
+            self.bytes,
+            self.referer.as_deref().unwrap_or("-"),
+            self.user_agent.as_deref().unwrap_or("-"),
+            self.duration,
+            self.request_id.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+enum WriterMessage {
+    Line {
+        to_stdout: bool,
+        site: Option<String>,
+        line: String,
+    },
+    Flush(SyncSender<()>),
+}
+
+/// Writes log lines on its own thread, so requests never wait for stdout or the disk
+struct LogWriter {
+    stdout: BufWriter<Stdout>,
+    site_logs_dir: Option<PathBuf>,
+    /// Open site logs, with when each was last written to
+    site_logs: HashMap<String, (BufWriter<File>, u64)>,
+    writes: u64,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LogWriter {
+    fn new(site_logs_dir: Option<PathBuf>, dropped: Arc<AtomicU64>) -> Self {
+        LogWriter {
+            stdout: BufWriter::new(io::stdout()),
+            site_logs_dir,
+            site_logs: HashMap::new(),
+            writes: 0,
+            dropped,
+        }
+    }
+
+    fn open_site_log(&mut self, hostname: &str) -> io::Result<&mut (BufWriter<File>, u64)> {
+        if !self.site_logs.contains_key(hostname) {
+            let dir = match &self.site_logs_dir {
+                Some(d) => d,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, "No log directory")),
+            };
+            fs::create_dir_all(dir)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(format!("{}.log", hostname)))?;
+            if self.site_logs.len() >= MAX_OPEN_SITE_LOGS {
+                let least_recent = self
+                    .site_logs
+                    .iter()
+                    .min_by_key(|(_, (_, used))| *used)
+                    .map(|(h, _)| h.clone());
+                // Dropping the writer flushes and closes it
+                if let Some(h) = least_recent {
+                    self.site_logs.remove(&h);
+                }
+            }
+            self.site_logs
+                .insert(hostname.to_owned(), (BufWriter::new(file), 0));
+        }
+        Ok(self.site_logs.get_mut(hostname).unwrap())
+    }
+
+    fn write_site_log(&mut self, hostname: &str, line: &str) {
+        self.writes += 1;
+        let writes = self.writes;
+        let written = self.open_site_log(hostname).and_then(|(file, used)| {
+            *used = writes;
+            writeln!(file, "{}", line)
+        });
+        if let Err(e) = written {
+            error!("Failed to write access log for {}: {}", hostname, e);
+        }
+    }
+
+    fn handle(&mut self, message: WriterMessage) {
+        match message {
+            WriterMessage::Line {
+                to_stdout,
+                site,
+                line,
+            } => {
+                if to_stdout {
+                    let _ = writeln!(self.stdout, "{}", line);
+                }
+                if let Some(site) = site {
+                    self.write_site_log(&site, &line);
+                }
+            }
+            WriterMessage::Flush(done) => {
+                self.flush();
+                let _ = done.send(());
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let _ = self.stdout.flush();
+        for (hostname, (file, _)) in self.site_logs.iter_mut() {
+            if let Err(e) = file.flush() {
+                error!("Failed to write access log for {}: {}", hostname, e);
+            }
+        }
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            error!(
+                "Dropped {} access log lines, as writing fell behind",
+                dropped
+            );
+        }
+    }
+
+    fn run(mut self, messages: Receiver<WriterMessage>) {
+        while let Ok(message) = messages.recv() {
+            self.handle(message);
+            // Write whatever else is waiting, and only flush once caught up
+            while let Ok(message) = messages.try_recv() {
+                self.handle(message);
+            }
+            self.flush();
+        }
+    }
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    filter: AccessLogFilter,
+    /// Only needed for JSON, or writing each site's log to `<hostname>.log`
+    writer: Option<SyncSender<WriterMessage>>,
+    has_site_logs: bool,
+    dropped: Arc<AtomicU64>,
+    requests: AtomicU64,
+}
+
+impl AccessLog {
+    pub fn new(
+        format: AccessLogFormat,
+        filter: AccessLogFilter,
+        site_logs_dir: Option<PathBuf>,
+    ) -> Self {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let has_site_logs = site_logs_dir.is_some();
+        let writer = if format == AccessLogFormat::Json || has_site_logs {
+            let (tx, rx) = sync_channel(WRITE_QUEUE_SIZE);
+            let writer = LogWriter::new(site_logs_dir, dropped.clone());
+            thread::Builder::new()
+                .name(String::from("access-log"))
+                .spawn(move || writer.run(rx))
+                .expect("Failed to start access log writer");
+            Some(tx)
+        } else {
+            None
+        };
+        AccessLog {
+            format,
+            filter,
+            writer,
+            has_site_logs,
+            dropped,
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// Spread sampled requests evenly, rather than randomly
+    fn is_sampled(&self) -> bool {
+        if self.filter.sample_rate >= 1.0 {
+            return true;
+        }
+        let n = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.filter.sample_rate).floor() > (n * self.filter.sample_rate).floor()
+    }
+
+    pub fn should_log(&self, path: &str, status: u16) -> bool {
+        if is_health_check(path) {
+            if !self.filter.log_health_checks {
+                return false;
+            }
+        } else if is_internal(path) && !self.filter.log_internal {
+            return false;
+        }
+        status >= 400 || self.is_sampled()
+    }
+
+    fn format_entry(&self, entry: &AccessLogEntry) -> String {
+        match self.format {
+            AccessLogFormat::Text => entry.to_text(),
+            AccessLogFormat::Json => serde_json::to_string(entry).expect("Failed to log request"),
+        }
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        if !self.should_log(&entry.path, entry.status) {
+            return;
+        }
+        let line = self.format_entry(entry);
+        if self.format == AccessLogFormat::Text {
+            info!(target: ACCESS_LOG_TARGET, "{}", line);
+        }
+        let site = entry.site.clone().filter(|_| self.has_site_logs);
+        let to_stdout = self.format == AccessLogFormat::Json;
+        if let Some(writer) = self.writer.as_ref().filter(|_| to_stdout || site.is_some()) {
+            let message = WriterMessage::Line {
+                to_stdout,
+                site,
+                line,
+            };
+            if let Err(TrySendError::Full(_)) = writer.try_send(message) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Wait for everything logged so far to be written
+    pub fn flush(&self) {
+        if let Some(writer) = &self.writer {
+            let (tx, rx) = sync_channel(1);
+            if writer.send(WriterMessage::Flush(tx)).is_ok() {
+                let _ = rx.recv();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::TestDir;
+
+    fn get_entry(path: &str, status: u16) -> AccessLogEntry {
+        AccessLogEntry {
+            client_ip: Some(String::from("192.0.2.1")),
+            method: String::from("GET"),
+            path: path.to_owned(),
+            version: String::from("HTTP/1.1"),
+            status,
+            bytes: 16,
+            site: Some(String::from("localhost")),
+            ..AccessLogEntry::new()
+        }
+    }
+
+    #[test]
+    fn test_format_from_name() {
+        assert_eq!(
+            AccessLogFormat::from_name("JSON"),
+            Some(AccessLogFormat::Json)
+        );
+        assert_eq!(
+            AccessLogFormat::from_name("text"),
+            Some(AccessLogFormat::Text)
+        );
+        assert_eq!(AccessLogFormat::from_name("xml"), None);
+    }
+
+    #[test]
+    fn test_to_text() {
+        let mut entry = get_entry("/index.html", 200);
+        entry.query = String::from("a=b");
+        entry.duration = 0.5;
+        assert_eq!(
+            entry.to_text(),
+            r#"192.0.2.1 "GET /index.html?a=b HTTP/1.1" 200 16 "-" "-" 0.500000 -"#
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let log = AccessLog::new(AccessLogFormat::Text, AccessLogFilter::default(), None);
+        assert!(log.should_log("/index.html", 200));
+        assert!(!log.should_log("/.traefik-pages/provider", 200));
+        assert!(!log.should_log("/.traefik-pages/health", 200));
+
+        let log = AccessLog::new(
+            AccessLogFormat::Text,
+            AccessLogFilter {
+                log_internal: true,
+                log_health_checks: false,
+                sample_rate: 0.25,
+            },
+            None,
+        );
+        assert!(!log.should_log("/.traefik-pages/ready", 500));
+        let sampled = (0..100)
+            .filter(|_| log.should_log("/.traefik-pages/provider", 200))
+            .count();
+        assert_eq!(sampled, 25);
+        assert!(log.should_log("/missing.html", 404));
+    }
+
+    #[test]
+    fn test_site_logs() {
+        let dir = TestDir::new("access-logs");
+        let log = AccessLog::new(
+            AccessLogFormat::Json,
+            AccessLogFilter::default(),
+            Some(dir.to_path_buf()),
+        );
+        log.log(&get_entry("/", 200));
+        log.log(&get_entry("/missing.html", 404));
+        log.flush();
+
+        let contents = fs::read_to_string(dir.join("localhost.log")).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["path"], "/missing.html");
+        assert_eq!(lines[1]["status"], 404);
+        assert_eq!(lines[1]["client_ip"], "192.0.2.1");
+    }
+
+    #[test]
+    fn test_site_logs_closed_when_unused() {
+        let dir = TestDir::new("access-logs-lru");
+        let mut writer = LogWriter::new(Some(dir.to_path_buf()), Arc::new(AtomicU64::new(0)));
+        for i in 0..=MAX_OPEN_SITE_LOGS {
+            writer.write_site_log(&format!("site{}.example.com", i), "line");
+        }
+        writer.write_site_log("site1.example.com", "again");
+        assert_eq!(writer.site_logs.len(), MAX_OPEN_SITE_LOGS);
+        assert!(!writer.site_logs.contains_key("site0.example.com"));
+
+        // Closed logs were flushed, and can be reopened
+        writer.write_site_log("site0.example.com", "reopened");
+        writer.flush();
+        assert_eq!(
+            fs::read_to_string(dir.join("site0.example.com.log")).unwrap(),
+            "line\nreopened\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("site1.example.com.log")).unwrap(),
+            "line\nagain\n"
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use crate::ip_rules::{is_ip_allowed, IpRange};
+use crate::site_config::{Credential, PathConfig, SiteConfig};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::HttpResponse;
+use actix_web_httpauth::headers::authorization::{Basic, Scheme};
+use constant_time_eq::constant_time_eq;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Why a request was refused
+#[derive(Debug, PartialEq)]
+pub enum AccessDenied {
+    /// Credentials are needed, and weren't given or were wrong
+    Unauthorized,
+    Forbidden,
+}
+
+impl AccessDenied {
+    pub fn into_response(self, realm: &str) -> HttpResponse {
+        match self {
+            AccessDenied::Unauthorized => HttpResponse::Unauthorized()
+                .header(WWW_AUTHENTICATE, format!(r#"Basic realm="{}""#, realm))
+                .finish(),
+            AccessDenied::Forbidden => HttpResponse::Forbidden().finish(),
+        }
+    }
+}
+
+/// The rules from either a site, or a path within it
+struct AccessRules<'a> {
+    allow_ips: &'a [IpRange],
+    deny_ips: &'a [IpRange],
+    credentials: &'a [Credential],
+    required_headers: &'a BTreeMap<String, String>,
+    /// Only to be seen through a signed URL
+    private: bool,
+}
+
+impl<'a> From<&'a SiteConfig> for AccessRules<'a> {
+    fn from(config: &'a SiteConfig) -> Self {
+        AccessRules {
+            allow_ips: &config.allow_ips,
+            deny_ips: &config.deny_ips,
+            credentials: &config.credentials,
+            required_headers: &config.required_headers,
+            private: false,
+        }
+    }
+}
+
+impl<'a> From<&'a PathConfig> for AccessRules<'a> {
+    fn from(path: &'a PathConfig) -> Self {
+        AccessRules {
+            allow_ips: &path.allow_ips,
+            deny_ips: &path.deny_ips,
+            credentials: &path.credentials,
+            required_headers: &path.required_headers,
+            private: path.private,
+        }
+    }
+}
+
+impl AccessRules<'_> {
+    fn is_ip_allowed(&self, ip: Option<IpAddr>) -> bool {
+        if self.allow_ips.is_empty() && self.deny_ips.is_empty() {
+            return true;
+        }
+        ip.is_some_and(|ip| is_ip_allowed(&ip, self.allow_ips, self.deny_ips))
+    }
+
+    fn has_required_headers(&self, headers: &HeaderMap) -> bool {
+        self.required_headers
+            .iter()
+            .all(|(name, value)| headers.get(name.as_str()).is_some_and(|v| v == value))
+    }
+
+    /// The user the credentials are for, if they're needed and valid
+    fn authenticate(&self, basic: Option<&Basic>) -> Result<Option<String>, AccessDenied> {
+        if self.credentials.is_empty() {
+            return Ok(None);
+        }
+        let basic = basic.ok_or(AccessDenied::Unauthorized)?;
+        let password_sha256 = format!(
+            "{:x}",
+            Sha256::digest(basic.password().map_or("", |p| p.as_ref()).as_bytes())
+        );
+        // Check every credential, so the time taken doesn't depend on which matched
+        self.credentials
+            .iter()
+            .fold(None, |found, credential| {
+                let matches = constant_time_eq(
+                    credential.password_sha256.to_lowercase().as_bytes(),
+                    password_sha256.as_bytes(),
+                ) && credential.username == basic.user_id().as_ref();
+                if matches {
+                    Some(credential.username.clone())
+                } else {
+                    found
+                }
+            })
+            .map(Some)
+            .ok_or(AccessDenied::Unauthorized)
+    }
+}
+
+/// Decide whether a client may see a path within a site, going by the site's rules and those of
+/// every path the request is within. Returns the authenticated user, if there is one.
+///
+/// `site_path` must already be normalized. If any IP lists apply, clients with an unknown
+/// address are refused. `is_signed` checks whether the request has a valid signed URL, which
+/// private paths require, and is only called for them.
+pub fn check_access(
+    config: &SiteConfig,
+    site_path: &str,
+    client_ip: Option<IpAddr>,
+    headers: &HeaderMap,
+    is_signed: impl FnOnce() -> bool,
+) -> Result<Option<String>, AccessDenied> {
+    let rules: Vec<AccessRules> = std::iter::once(AccessRules::from(config))
+        .chain(
+            config
+                .paths
+                .iter()
+                .filter(|path| path.matches(site_path))
+                .map(AccessRules::from),
+        )
+        .collect();
+
+    // Credentials won't help with these, so they're checked first
+    if !rules
+        .iter()
+        .all(|r| r.is_ip_allowed(client_ip) && r.has_required_headers(headers))
+    {
+        return Err(AccessDenied::Forbidden);
+    }
+    if rules.iter().any(|r| r.private) && !is_signed() {
+        return Err(AccessDenied::Forbidden);
+    }
+
+    let basic = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| Basic::parse(value).ok());
+    let mut user = None;
+    for r in rules.iter() {
+        if let Some(u) = r.authenticate(basic.as_ref())? {
+            user = Some(u);
+        }
+    }
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::header::IntoHeaderValue;
+    use actix_web::http::HeaderValue;
+
+    const PASSWORD_SHA256: &str =
+        "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8";
+
+    fn get_config() -> SiteConfig {
+        SiteConfig::parse(&format!(
+            r#"
+            deny_ips = ["192.0.2.1"]
+
+            [[paths]]
+            prefix = "staff/"
+            allow_ips = ["10.8.0.0/16"]
+
+            [[paths]]
+            prefix = "admin/"
+            required_headers = {{ "X-Team" = "platform" }}
+            [[paths.credentials]]
+            username = "admin"
+            password_sha256 = "{}"
+            "#,
+            PASSWORD_SHA256.to_uppercase()
+        ))
+        .unwrap()
+    }
+
+    fn get_headers(values: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(name.parse().unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_ip_lists() {
+        let config = get_config();
+        let headers = HeaderMap::new();
+        let office = Some("10.8.1.1".parse().unwrap());
+        let public = Some("198.51.100.1".parse().unwrap());
+        let blocked = Some("192.0.2.1".parse().unwrap());
+
+        assert_eq!(
+            check_access(&config, "index.html", office, &headers, || false),
+            Ok(None)
+        );
+        assert_eq!(
+            check_access(&config, "index.html", public, &headers, || false),
+            Ok(None)
+        );
+        for (path, ip) in [
+            ("index.html", blocked),
+            ("index.html", None),
+            ("staff/index.html", public),
+        ] {
+            assert_eq!(
+                check_access(&config, path, ip, &headers, || false),
+                Err(AccessDenied::Forbidden)
+            );
+        }
+        assert_eq!(
+            check_access(&config, "staff/index.html", office, &headers, || false),
+            Ok(None)
+        );
+
+        assert_eq!(
+            check_access(&SiteConfig::default(), "index.html", None, &headers, || {
+                false
+            }),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_credentials_and_headers() {
+        let config = get_config();
+        let ip = Some("10.8.1.1".parse().unwrap());
+        let credentials = |password: &str| {
+            let value = Basic::new("admin", Some(password.to_owned()))
+                .try_into()
+                .unwrap();
+            value.to_str().unwrap().to_owned()
+        };
+
+        assert_eq!(
+            check_access(&config, "admin/", ip, &HeaderMap::new(), || false),
+            Err(AccessDenied::Forbidden)
+        );
+        assert_eq!(
+            check_access(
+                &config,
+                "admin/",
+                ip,
+                &get_headers(&[("x-team", "platform")]),
+                || false
+            ),
+            Err(AccessDenied::Unauthorized)
+        );
+        assert_eq!(
+            check_access(
+                &config,
+                "admin/",
+                ip,
+                &get_headers(&[
+                    ("x-team", "platform"),
+                    ("authorization", &credentials("wrong"))
+                ]),
+                || false
+            ),
+            Err(AccessDenied::Unauthorized)
+        );
+        assert_eq!(
+            check_access(
+                &config,
+                "admin/",
+                ip,
+                &get_headers(&[
+                    ("x-team", "platform"),
+                    ("authorization", &credentials("password"))
+                ]),
+                || false
+            ),
+            Ok(Some(String::from("admin")))
+        );
+    }
+
+    #[test]
+    fn test_private_paths() {
+        let config = SiteConfig::parse("[[paths]]\nprefix = 'reports/'\nprivate = true").unwrap();
+        let headers = HeaderMap::new();
+        assert_eq!(
+            check_access(&config, "index.html", None, &headers, || false),
+            Ok(None)
+        );
+        assert_eq!(
+            check_access(&config, "reports/q3.pdf", None, &headers, || false),
+            Err(AccessDenied::Forbidden)
+        );
+        assert_eq!(
+            check_access(&config, "reports/q3.pdf", None, &headers, || true),
+            Ok(None)
+        );
+        // Signatures are only checked for private paths
+        assert_eq!(
+            check_access(&config, "index.html", None, &headers, || panic!()),
+            Ok(None)
+        );
+    }
+}
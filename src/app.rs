@@ -1,16 +1,52 @@
+use crate::access_log::AccessLogEntry;
+use crate::request_id::{RequestId, REQUEST_ID_HEADER};
 use crate::routes;
 use crate::settings::Settings;
 use crate::VERSION;
-use actix_web::http::header;
+use actix_web::dev::Service;
+use actix_web::http::{header, HeaderName, HeaderValue};
 use actix_web::middleware::DefaultHeaders;
 use actix_web::web::ServiceConfig;
+use actix_web::HttpMessage;
+use std::sync::Arc;
+use std::time::Instant;
 
 pub fn configure_app(cfg: &mut ServiceConfig, settings: Settings) {
-    let service = routes::get_routes(&settings).wrap(
-        DefaultHeaders::new()
-            .header(header::SERVER, format!("traefik-pages {}", VERSION))
-            .header(header::CACHE_CONTROL, "max-age=0, must-revalidate, public"),
-    );
+    let metrics = settings.metrics.clone();
+    let access_log = settings.access_log.clone();
+    let trusted_proxies = Arc::new(settings.trusted_proxies.clone());
+    let service = routes::get_routes(&settings)
+        .wrap(
+            DefaultHeaders::new()
+                .header(header::SERVER, format!("traefik-pages {}", VERSION))
+                .header(header::CACHE_CONTROL, "max-age=0, must-revalidate, public"),
+        )
+        .wrap_fn(move |req, srv| {
+            let started = Instant::now();
+            let (metrics, access_log, trusted_proxies) =
+                (metrics.clone(), access_log.clone(), trusted_proxies.clone());
+            let request_id = RequestId::from_head(req.head());
+            req.extensions_mut().insert(request_id.clone());
+            let response = srv.call(req);
+            async move {
+                let mut response = response.await?;
+                if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                let duration = started.elapsed();
+                let entry = AccessLogEntry::from_response(&response, &trusted_proxies, duration);
+                metrics.record_request(
+                    entry.site.as_deref().unwrap_or_default(),
+                    response.status(),
+                    entry.bytes,
+                    duration,
+                );
+                access_log.log(&entry);
+                Ok(response)
+            }
+        });
     cfg.data(settings).service(service);
 }
 
@@ -18,7 +54,6 @@ pub fn configure_app(cfg: &mut ServiceConfig, settings: Settings) {
 mod tests {
     use super::*;
     use crate::test_utils::get_test_settings;
-    use actix_web::http::HeaderValue;
     use actix_web::web::Bytes;
     use actix_web::{test, App};
 
@@ -56,4 +91,29 @@ mod tests {
             HeaderValue::from_static("max-age=0, must-revalidate, public")
         );
     }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let request = test::TestRequest::get()
+            .uri("/")
+            .header(header::HOST, "localhost")
+            .header(REQUEST_ID_HEADER, "from-traefik")
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(
+            response.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "from-traefik"
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/missing.html")
+            .header(header::HOST, "localhost")
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 404);
+        assert!(response.headers().get(REQUEST_ID_HEADER).is_some());
+    }
 }
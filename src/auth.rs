@@ -1,41 +1,217 @@
 use constant_time_eq::constant_time_eq;
 
-use actix_web::dev::RequestHead;
+use crate::client_ip::TrustedProxies;
+use crate::request_id::RequestId;
+use crate::throttle::FailureThrottle;
+use actix_web::dev::{RequestHead, ServiceRequest};
 use actix_web::guard::Guard;
-use actix_web::http::header::AUTHORIZATION;
-use actix_web_httpauth::headers::authorization::Basic;
+use actix_web::http::header::{HeaderValue, AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE};
+use actix_web::HttpResponse;
 use actix_web_httpauth::headers::authorization::Scheme;
+use actix_web_httpauth::headers::authorization::{Basic, Bearer};
+use log::warn;
+use serde_derive::Deserialize;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
 
-pub struct BasicAuthGuard {
-    password: String,
+pub const AUDIT_LOG_TARGET: &str = "traefik_pages::audit";
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum TokenScope {
+    #[serde(rename = "provider:read")]
+    ProviderRead,
+    #[serde(rename = "sites:read")]
+    SitesRead,
+    #[serde(rename = "deploy:write")]
+    DeployWrite,
+    #[serde(rename = "metrics:read")]
+    MetricsRead,
+    #[serde(rename = "urls:sign")]
+    UrlsSign,
+    /// Grants every other scope
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl TokenScope {
+    pub fn from_name(name: &str) -> Option<TokenScope> {
+        match name {
+            "provider:read" => Some(TokenScope::ProviderRead),
+            "sites:read" => Some(TokenScope::SitesRead),
+            "deploy:write" => Some(TokenScope::DeployWrite),
+            "metrics:read" => Some(TokenScope::MetricsRead),
+            "urls:sign" => Some(TokenScope::UrlsSign),
+            "admin" => Some(TokenScope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// A named secret granting access to parts of the internal API
+#[derive(Clone, Deserialize)]
+pub struct ApiToken {
+    pub name: String,
+    secret: String,
+    pub scopes: Vec<TokenScope>,
+}
+
+impl ApiToken {
+    pub fn new(name: &str, secret: &str, scopes: Vec<TokenScope>) -> Self {
+        ApiToken {
+            name: name.to_owned(),
+            secret: secret.to_owned(),
+            scopes,
+        }
+    }
+
+    pub fn has_scope(&self, scope: TokenScope) -> bool {
+        self.scopes
+            .iter()
+            .any(|s| *s == scope || *s == TokenScope::Admin)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokensFile {
+    tokens: Vec<ApiToken>,
+}
+
+/// Read tokens from a TOML file containing a `[[tokens]]` table for each token
+pub fn load_tokens_file(path: &Path) -> io::Result<Vec<ApiToken>> {
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str::<TokensFile>(&contents)
+        .map(|f| f.tokens)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parse a token given as `name:secret:scope+scope`
+pub fn parse_token(value: &str) -> Result<ApiToken, String> {
+    let (name, rest) = value
+        .split_once(':')
+        .ok_or_else(|| format!("Token {} must be in the format name:secret:scopes", value))?;
+    let (secret, scopes) = rest
+        .split_once(':')
+        .ok_or_else(|| format!("Token {} must be in the format name:secret:scopes", name))?;
+    let scopes = scopes
+        .split('+')
+        .map(|s| TokenScope::from_name(s).ok_or_else(|| format!("Unknown scope {}", s)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ApiToken::new(name, secret, scopes))
+}
+
+/// Get the secret from either a Bearer token, or Basic credentials.
+///
+/// Basic credentials can either be the secret as the username (as Traefik's HTTP provider
+/// sends it), or any username with the secret as the password.
+fn get_secret(header_value: &HeaderValue) -> Option<String> {
+    if let Ok(bearer) = Bearer::parse(header_value) {
+        return Some(bearer.token().to_string());
+    }
+    let basic = Basic::parse(header_value).ok()?;
+    match basic.password() {
+        Some(password) => Some(password.to_string()),
+        None => Some(basic.user_id().to_string()),
+    }
+}
+
+/// Find the token a request was made with, if any
+pub fn authenticate<'a>(tokens: &'a [ApiToken], request: &RequestHead) -> Option<&'a ApiToken> {
+    let secret = get_secret(request.headers().get(AUTHORIZATION)?)?;
+    // Check every token, so the time taken doesn't depend on which matched
+    tokens.iter().fold(None, |found, token| {
+        if constant_time_eq(token.secret.as_bytes(), secret.as_bytes()) {
+            Some(token)
+        } else {
+            found
+        }
+    })
+}
+
+fn get_client_description(ip: Option<IpAddr>) -> String {
+    ip.map(|ip| ip.to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+/// Check a request to the internal API is made with a valid token, returning the token's name.
+///
+/// Otherwise, the response to send instead is returned: a challenge for missing or invalid
+/// credentials, or `429` if the client has failed too many times recently. Valid credentials are
+/// always accepted, so clients sharing an address (such as behind an untrusted proxy) can't lock
+/// each other out.
+pub fn authorize(
+    req: &ServiceRequest,
+    tokens: &[ApiToken],
+    throttle: &FailureThrottle,
+    trusted_proxies: &TrustedProxies,
+) -> Result<String, HttpResponse> {
+    // Refused before the credentials are checked, so a locked out client can't keep guessing
+    let ip = trusted_proxies.client_ip(req.head());
+    if let Some(retry_after) = ip.and_then(|ip| throttle.retry_after(ip)) {
+        warn!(
+            target: AUDIT_LOG_TARGET,
+            "[{}] Rejected {} {} from {}: too many failed attempts",
+            RequestId::of(req.head()),
+            req.method(),
+            req.path(),
+            get_client_description(ip)
+        );
+        return Err(HttpResponse::TooManyRequests()
+            .header(RETRY_AFTER, (retry_after.as_secs() + 1).to_string())
+            .finish());
+    }
+
+    if let Some(token) = authenticate(tokens, req.head()) {
+        return Ok(token.name.clone());
+    }
+
+    if let Some(ip) = ip {
+        throttle.record_failure(ip);
+    }
+    warn!(
+        target: AUDIT_LOG_TARGET,
+        "[{}] Rejected {} {} from {}: invalid credentials",
+        RequestId::of(req.head()),
+        req.method(),
+        req.path(),
+        get_client_description(ip)
+    );
+    Err(HttpResponse::Unauthorized()
+        .header(
+            WWW_AUTHENTICATE,
+            r#"Basic realm="traefik-pages", Bearer realm="traefik-pages""#,
+        )
+        .finish())
+}
+
+/// Matches requests made with a token granting a scope
+pub struct TokenGuard {
+    tokens: Vec<ApiToken>,
+    scope: TokenScope,
 }
 
-impl Guard for BasicAuthGuard {
+impl Guard for TokenGuard {
     fn check(&self, request: &RequestHead) -> bool {
-        match request
-            .headers()
-            .get(AUTHORIZATION)
-            .and_then(|header_value| Basic::parse(header_value).ok())
-        {
-            Some(credentials) => {
-                credentials.password().is_none()
-                    && constant_time_eq(self.password.as_bytes(), credentials.user_id().as_bytes())
-            }
+        match authenticate(&self.tokens, request) {
+            Some(token) => token.has_scope(self.scope),
             None => false,
         }
     }
 }
 
-impl BasicAuthGuard {
-    pub fn new(password: &str) -> BasicAuthGuard {
-        BasicAuthGuard {
-            password: password.to_owned(),
+impl TokenGuard {
+    pub fn new(tokens: &[ApiToken], scope: TokenScope) -> TokenGuard {
+        TokenGuard {
+            tokens: tokens.to_vec(),
+            scope,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     use crate::app::configure_app;
     use crate::test_utils::{auth_credentials, get_test_settings, TEST_PASSWORD};
     use actix_web::http::header;
@@ -48,6 +224,20 @@ mod tests {
         format!("/{}/provider", INTERNAL_ROUTE_PREFIX)
     }
 
+    async fn get_status(path: &str, authorization: Option<String>) -> u16 {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let mut request = test::TestRequest::get().uri(path);
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        test::call_service(&mut app, request.to_request())
+            .await
+            .status()
+            .as_u16()
+    }
+
     #[tokio::test]
     async fn test_requires_auth_header() {
         let mut app =
@@ -55,7 +245,14 @@ mod tests {
                 .await;
         let request = test::TestRequest::get().uri(&get_test_path()).to_request();
         let response = test::call_service(&mut app, request).await;
-        assert_eq!(response.status(), 404);
+        assert_eq!(response.status(), 401);
+        assert!(response
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("Basic "));
     }
 
     #[tokio::test]
@@ -84,7 +281,7 @@ mod tests {
             )
             .to_request();
         let response = test::call_service(&mut app, request).await;
-        assert_eq!(response.status(), 404);
+        assert_eq!(response.status(), 401);
     }
 
     #[tokio::test]
@@ -100,6 +297,155 @@ mod tests {
             )
             .to_request();
         let response = test::call_service(&mut app, request).await;
-        assert_eq!(response.status(), 404);
+        assert_eq!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token() {
+        assert_eq!(
+            get_status(&get_test_path(), Some(format!("Bearer {}", TEST_PASSWORD))).await,
+            200
+        );
+        assert_eq!(
+            get_status(&get_test_path(), Some(String::from("Bearer wrong"))).await,
+            401
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_as_password() {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let request = test::TestRequest::get()
+            .uri(&get_test_path())
+            .header(header::AUTHORIZATION, Basic::new("ci", Some(TEST_PASSWORD)))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_scopes_enforced() {
+        let provider_credentials = String::from("Bearer provider-secret");
+        assert_eq!(
+            get_status(&get_test_path(), Some(provider_credentials.clone())).await,
+            200
+        );
+        assert_eq!(
+            get_status(
+                &format!("/{}/sites", INTERNAL_ROUTE_PREFIX),
+                Some(provider_credentials)
+            )
+            .await,
+            403
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_internal_path() {
+        let path = format!("/{}/unknown", INTERNAL_ROUTE_PREFIX);
+        assert_eq!(get_status(&path, None).await, 401);
+        assert_eq!(
+            get_status(&path, Some(format!("Bearer {}", TEST_PASSWORD))).await,
+            404
+        );
+        assert_eq!(
+            get_status(&path, Some(String::from("Bearer provider-secret"))).await,
+            403
+        );
+    }
+
+    #[tokio::test]
+    async fn test_throttles_failures() {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let get_request = |addr: &str, secret: &str| {
+            test::TestRequest::get()
+                .uri(&get_test_path())
+                .peer_addr(addr.parse().unwrap())
+                .header(header::AUTHORIZATION, format!("Bearer {}", secret))
+                .to_request()
+        };
+
+        for _ in 0..3 {
+            let response =
+                test::call_service(&mut app, get_request("10.0.0.1:1234", "wrong")).await;
+            assert_eq!(response.status(), 401);
+        }
+
+        // Further attempts are refused outright, even with correct credentials
+        let response = test::call_service(&mut app, get_request("10.0.0.1:1234", "wrong")).await;
+        assert_eq!(response.status(), 429);
+        assert!(response.headers().get(header::RETRY_AFTER).is_some());
+        let response =
+            test::call_service(&mut app, get_request("10.0.0.1:1234", TEST_PASSWORD)).await;
+        assert_eq!(response.status(), 429);
+
+        let response =
+            test::call_service(&mut app, get_request("10.0.0.2:1234", TEST_PASSWORD)).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn test_has_scope() {
+        let token = ApiToken::new("ci", "secret", vec![TokenScope::DeployWrite]);
+        assert!(token.has_scope(TokenScope::DeployWrite));
+        assert!(!token.has_scope(TokenScope::ProviderRead));
+
+        let admin = ApiToken::new("admin", "secret", vec![TokenScope::Admin]);
+        assert!(admin.has_scope(TokenScope::DeployWrite));
+        assert!(admin.has_scope(TokenScope::SitesRead));
+    }
+
+    #[test]
+    fn test_parse_token() {
+        let token = parse_token("traefik:s3cret:provider:read+sites:read").unwrap();
+        assert_eq!(token.name, "traefik");
+        assert_eq!(token.secret, "s3cret");
+        assert_eq!(
+            token.scopes,
+            vec![TokenScope::ProviderRead, TokenScope::SitesRead]
+        );
+
+        assert!(parse_token("traefik").is_err());
+        assert!(parse_token("traefik:s3cret").is_err());
+        assert!(parse_token("traefik:s3cret:everything").is_err());
+    }
+
+    #[test]
+    fn test_load_tokens_file() {
+        let path = std::env::temp_dir().join("traefik-pages-test-tokens.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [[tokens]]
+            name = "traefik"
+            secret = "one"
+            scopes = ["provider:read"]
+
+            [[tokens]]
+            name = "ci"
+            secret = "two"
+            scopes = ["deploy:write", "sites:read"]
+            "#,
+        )
+        .unwrap();
+        let tokens = load_tokens_file(&path).unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[1].name, "ci");
+        assert_eq!(
+            tokens[1].scopes,
+            vec![TokenScope::DeployWrite, TokenScope::SitesRead]
+        );
+
+        std::fs::write(
+            &path,
+            "[[tokens]]\nname = \"bad\"\nsecret = \"x\"\nscopes = [\"root\"]",
+        )
+        .unwrap();
+        assert!(load_tokens_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,212 @@
+use crate::proxy::traefik::build_provider_config;
+use crate::settings::Settings;
+use crate::site::{is_valid_hostname, Site};
+use crate::site_config::SiteConfig;
+use serde_derive::Serialize;
+use std::io;
+use std::path::Path;
+use tokio::fs;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Something wrong with the sites on disk
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Relative to the sites root, or the name of a Traefik router
+    pub path: String,
+    pub problem: String,
+}
+
+impl Problem {
+    fn new(severity: Severity, path: impl AsRef<Path>, problem: impl Into<String>) -> Self {
+        Problem {
+            severity,
+            path: path.as_ref().display().to_string(),
+            problem: problem.into(),
+        }
+    }
+}
+
+/// Entries in the sites root which aren't served
+async fn check_sites_root(sites_root: &Path) -> io::Result<Vec<Problem>> {
+    let mut problems = vec![];
+    let mut entries = fs::read_dir(sites_root).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // Hidden entries are left alone deliberately, such as deploys in progress
+        if name.starts_with('.') {
+            continue;
+        }
+        if !entry.file_type().await?.is_dir() {
+            problems.push(Problem::new(
+                Severity::Warning,
+                name.as_ref(),
+                "Not a directory, so not served",
+            ));
+        } else if !is_valid_hostname(&name) {
+            problems.push(Problem::new(
+                Severity::Warning,
+                name.as_ref(),
+                "Not a valid hostname, so not served",
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
+async fn check_site(sites_root: &Path, site: &Site) -> io::Result<Vec<Problem>> {
+    let mut problems = vec![];
+    let site_path = site
+        .get_root()
+        .strip_prefix(sites_root)
+        .unwrap_or(site.get_root());
+
+    if let Some(config_path) = site.get_config_path() {
+        let config_name = config_path.strip_prefix(sites_root).unwrap_or(config_path);
+        if let Some(ref error) = site.config_error {
+            problems.push(Problem::new(
+                Severity::Error,
+                config_name,
+                format!("Invalid config, so the site isn't served: {}", error),
+            ));
+        } else {
+            for key in SiteConfig::find_unknown_keys(&fs::read_to_string(config_path).await?)? {
+                problems.push(Problem::new(
+                    Severity::Error,
+                    config_name,
+                    format!("Unknown key {}", key),
+                ));
+            }
+        }
+    }
+
+    for error in site.nested_config_errors.iter() {
+        problems.push(Problem::new(
+            Severity::Error,
+            error
+                .config_path
+                .strip_prefix(sites_root)
+                .unwrap_or(&error.config_path),
+            format!(
+                "Invalid config, so served as part of the parent site: {}",
+                error.error
+            ),
+        ));
+    }
+
+    if site.is_valid() && site.config.dir_index && site.get_file_for_path("").await.is_err() {
+        problems.push(Problem::new(
+            Severity::Warning,
+            site_path,
+            format!("No {} at the root of the site", site.config.dir_index_name),
+        ));
+    }
+
+    Ok(problems)
+}
+
+/// Find every problem with the sites, straight from disk
+pub async fn check_sites(sites_root: &Path) -> io::Result<Vec<Problem>> {
+    let mut problems = check_sites_root(sites_root).await?;
+
+    let mut sites = Site::discover_all(sites_root).await?;
+    sites.sort_by(|a, b| a.get_root().cmp(b.get_root()));
+    for site in sites.iter() {
+        problems.extend(check_site(sites_root, site).await?);
+    }
+
+    Ok(problems)
+}
+
+/// Find problems with the provider config, which depends on the server's settings as well as the sites
+pub async fn check_provider_config(settings: &Settings) -> io::Result<Vec<Problem>> {
+    let (_, diagnostics) = build_provider_config(settings).await?;
+    Ok(diagnostics
+        .into_iter()
+        .map(|d| Problem::new(Severity::Error, &d.name, d.problem))
+        .collect())
+}
+
+pub fn has_errors(problems: &[Problem]) -> bool {
+    problems.iter().any(|p| p.severity == Severity::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::{get_test_settings, TestDir};
+
+    #[tokio::test]
+    async fn test_check_example_sites() {
+        let settings = get_test_settings();
+        let problems = check_sites(settings.registry.sites_root()).await.unwrap();
+        assert_eq!(
+            problems,
+            vec![Problem::new(
+                Severity::Warning,
+                "stray_file.txt",
+                "Not a directory, so not served"
+            )]
+        );
+        assert!(!has_errors(&problems));
+    }
+
+    #[tokio::test]
+    async fn test_check_problems() {
+        let root = TestDir::new("check");
+        for dir in ["bad@host", ".hidden", "empty.com"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        root.write("typo.com/index.html", "");
+        root.write("typo.com/pages.toml", "deny_prefix = ['a']");
+        root.write("broken.com/pages.toml", "dir_index = 1");
+        root.write("typo.com/docs/pages.toml", "site = ");
+
+        let problems = check_sites(&root).await.unwrap();
+        let mut found: Vec<(Severity, &str)> = problems
+            .iter()
+            .map(|p| (p.severity, p.path.as_str()))
+            .collect();
+        found.sort_by_key(|(_, path)| *path);
+        assert_eq!(
+            found,
+            vec![
+                (Severity::Warning, "bad@host"),
+                (Severity::Error, "broken.com/pages.toml"),
+                (Severity::Warning, "empty.com"),
+                (Severity::Error, "typo.com/docs/pages.toml"),
+                (Severity::Error, "typo.com/pages.toml"),
+            ]
+        );
+        assert!(problems
+            .iter()
+            .any(|p| p.problem == "Unknown key deny_prefix"));
+        assert!(has_errors(&problems));
+    }
+
+    #[tokio::test]
+    async fn test_check_provider_config() {
+        let mut settings = get_test_settings();
+        assert!(check_provider_config(&settings).await.unwrap().is_empty());
+
+        settings.default_middlewares = vec![String::from("compress")];
+        assert_eq!(
+            check_provider_config(&settings).await.unwrap(),
+            vec![Problem::new(
+                Severity::Error,
+                "tp-default",
+                "Unknown middleware compress"
+            )]
+        );
+    }
+}
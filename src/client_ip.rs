@@ -0,0 +1,150 @@
+use crate::ip_rules::IpRange;
+use actix_web::dev::RequestHead;
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// Reverse proxies whose `X-Forwarded-For` headers can be believed
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpRange>,
+}
+
+impl TrustedProxies {
+    /// Parse a list of CIDR ranges, or single addresses
+    pub fn parse(values: &[String]) -> Result<Self, String> {
+        let networks = values
+            .iter()
+            .map(|value| {
+                value
+                    .parse::<IpRange>()
+                    .map_err(|_| format!("Invalid trusted proxy {}", value))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(TrustedProxies { networks })
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// Whether a request came straight from a trusted proxy, so its forwarded headers can be
+    /// believed
+    pub fn is_proxied(&self, head: &RequestHead) -> bool {
+        head.peer_addr
+            .is_some_and(|addr| self.is_trusted(&addr.ip()))
+    }
+
+    /// The address of the client which made a request.
+    ///
+    /// If the request came through trusted proxies, this is the last address they forwarded for
+    /// which isn't itself a trusted proxy. Anything before that could have been made up by the
+    /// client, so is ignored.
+    pub fn client_ip(&self, head: &RequestHead) -> Option<IpAddr> {
+        let mut client_ip = head.peer_addr?.ip();
+        if !self.is_trusted(&client_ip) {
+            return Some(client_ip);
+        }
+
+        let forwarded_for: Vec<&str> = head
+            .headers()
+            .get_all(X_FORWARDED_FOR)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+
+        for value in forwarded_for.iter().rev() {
+            match parse_forwarded_ip(value) {
+                Some(ip) => {
+                    client_ip = ip;
+                    if !self.is_trusted(&ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        Some(client_ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    fn get_client_ip(proxies: &[&str], peer: &str, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let proxies: Vec<String> = proxies.iter().map(|p| p.to_string()).collect();
+        let mut request = TestRequest::get().peer_addr(peer.parse().unwrap());
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.header(X_FORWARDED_FOR, forwarded_for);
+        }
+        TrustedProxies::parse(&proxies)
+            .unwrap()
+            .client_ip(request.to_http_request().head())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(TrustedProxies::parse(&[String::from("10.0.0.0/8")]).is_ok());
+        assert!(TrustedProxies::parse(&[String::from("::1")]).is_ok());
+        assert!(TrustedProxies::parse(&[String::from("10.0.0.0/33")]).is_err());
+        assert!(TrustedProxies::parse(&[String::from("traefik")]).is_err());
+    }
+
+    #[test]
+    fn test_is_proxied() {
+        let proxies = TrustedProxies::parse(&[String::from("10.0.0.0/8")]).unwrap();
+        let request = |peer: &str| TestRequest::get().peer_addr(peer.parse().unwrap());
+        assert!(proxies.is_proxied(request("10.0.0.1:1234").to_http_request().head()));
+        assert!(!proxies.is_proxied(request("192.0.2.1:1234").to_http_request().head()));
+        assert!(!proxies.is_proxied(TestRequest::get().to_http_request().head()));
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        assert_eq!(
+            get_client_ip(&[], "10.0.0.1:1234", Some("192.0.2.1")),
+            Some("10.0.0.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_trusted_peer() {
+        let proxies = ["10.0.0.0/8"];
+        assert_eq!(
+            get_client_ip(&proxies, "10.0.0.1:1234", Some("192.0.2.1")),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        // The client can put anything at the start of the header, so only the end is used
+        assert_eq!(
+            get_client_ip(
+                &proxies,
+                "10.0.0.1:1234",
+                Some("198.51.100.1, 192.0.2.1, 10.0.0.2")
+            ),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            get_client_ip(&proxies, "10.0.0.1:1234", Some("10.0.0.3")),
+            Some("10.0.0.3".parse().unwrap())
+        );
+        assert_eq!(
+            get_client_ip(&proxies, "10.0.0.1:1234", Some("nonsense, 10.0.0.3")),
+            Some("10.0.0.3".parse().unwrap())
+        );
+        assert_eq!(
+            get_client_ip(&proxies, "10.0.0.1:1234", None),
+            Some("10.0.0.1".parse().unwrap())
+        );
+    }
+}
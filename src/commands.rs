@@ -0,0 +1,132 @@
+use crate::check::{check_sites, has_errors, Severity};
+use crate::kubernetes::{get_manifests, to_yaml, ManifestKind};
+use crate::proxy::{get_backend_config, Backend};
+use crate::settings::Settings;
+use log::error;
+use std::path::Path;
+
+async fn kubernetes(settings: &Settings, args: &[String]) -> i32 {
+    let kind = match args.first() {
+        Some(name) => match ManifestKind::from_name(name) {
+            Some(k) => k,
+            None => {
+                error!(
+                    "Unknown manifest kind {}. Expected ingressroute or ingress.",
+                    name
+                );
+                return 2;
+            }
+        },
+        None => ManifestKind::IngressRoute,
+    };
+
+    match get_manifests(settings, kind)
+        .await
+        .and_then(|manifests| to_yaml(&manifests))
+    {
+        Ok(yaml) => {
+            print!("{}", yaml);
+            0
+        }
+        Err(e) => {
+            error!("Failed to generate manifests: {}", e);
+            1
+        }
+    }
+}
+
+async fn config(settings: &Settings, args: &[String]) -> i32 {
+    let backend = match args.first().and_then(|name| Backend::from_name(name)) {
+        Some(b) => b,
+        None => {
+            error!("Expected a backend to generate config for: caddy or nginx.");
+            return 2;
+        }
+    };
+
+    match get_backend_config(settings, backend).await {
+        Ok(config) => {
+            print!("{}", config);
+            0
+        }
+        Err(e) => {
+            error!("Failed to generate config: {}", e);
+            1
+        }
+    }
+}
+
+/// Only needs the sites root, so works without the rest of the server's configuration
+pub async fn check(sites_root: &Path) -> i32 {
+    match check_sites(sites_root).await {
+        Ok(problems) => {
+            for problem in problems.iter() {
+                let severity = match problem.severity {
+                    Severity::Warning => "warning",
+                    Severity::Error => "error",
+                };
+                println!("{}: {}: {}", severity, problem.path, problem.problem);
+            }
+            if has_errors(&problems) {
+                1
+            } else {
+                0
+            }
+        }
+        Err(e) => {
+            error!("Failed to check sites: {}", e);
+            1
+        }
+    }
+}
+
+/// Run a command-line subcommand, returning the process exit code
+pub async fn run(command: &str, args: &[String], settings: &Settings) -> i32 {
+    match command {
+        "kubernetes" => kubernetes(settings, args).await,
+        "config" => config(settings, args).await,
+        _ => {
+            error!("Unknown command {}.", command);
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::get_test_settings;
+
+    #[tokio::test]
+    async fn test_kubernetes() {
+        let settings = get_test_settings();
+        assert_eq!(run("kubernetes", &[], &settings).await, 0);
+        assert_eq!(
+            run("kubernetes", &[String::from("ingress")], &settings).await,
+            0
+        );
+        assert_eq!(
+            run("kubernetes", &[String::from("gateway")], &settings).await,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config() {
+        let settings = get_test_settings();
+        assert_eq!(run("config", &[String::from("caddy")], &settings).await, 0);
+        assert_eq!(run("config", &[String::from("nginx")], &settings).await, 0);
+        assert_eq!(run("config", &[], &settings).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        assert_eq!(check(get_test_settings().registry.sites_root()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command() {
+        assert_eq!(run("unknown", &[], &get_test_settings()).await, 2);
+    }
+}
@@ -0,0 +1,644 @@
+use crate::releases::{self, RELEASES_DIR};
+use crate::site_config::{SiteConfig, CONFIG_FILENAME};
+use serde_derive::Serialize;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub enum DeployError {
+    /// The archive can't be deployed as it is
+    Invalid(String),
+    /// The archive extracts to more than the size or entry limit
+    TooLarge,
+    Io(io::Error),
+}
+
+impl From<io::Error> for DeployError {
+    fn from(e: io::Error) -> Self {
+        DeployError::Io(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ArchiveFormat {
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Detect the format from the archive's magic number
+    fn detect(archive: &[u8]) -> Option<ArchiveFormat> {
+        if archive.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveFormat::TarGz)
+        } else if archive.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(ArchiveFormat::TarZst)
+        } else if archive.starts_with(b"PK\x03\x04") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// How much an archive may extract to
+#[derive(Clone, Copy, Debug)]
+pub struct DeployLimits {
+    /// Total size of the extracted files, in bytes
+    pub max_size: u64,
+    /// Number of files and directories, so piles of empty files can't be extracted either
+    pub max_entries: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Deployment {
+    pub hostname: String,
+    pub files: usize,
+    pub bytes: u64,
+    /// Whether an existing site was replaced
+    pub replaced: bool,
+    /// ID of the release which was created and activated
+    pub release: String,
+}
+
+/// Only plain relative paths may be extracted, so nothing is written outside the site
+fn get_entry_path(name: &Path) -> Result<PathBuf, DeployError> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(c) => path.push(c),
+            Component::CurDir => {}
+            _ => {
+                return Err(DeployError::Invalid(format!(
+                    "Invalid path {}",
+                    name.display()
+                )))
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Writes archive entries into a staging directory, keeping track of what's been written
+struct Extractor<'a> {
+    root: &'a Path,
+    limits: DeployLimits,
+    entries: usize,
+    files: usize,
+    bytes: u64,
+    config_files: Vec<PathBuf>,
+}
+
+impl<'a> Extractor<'a> {
+    fn new(root: &'a Path, limits: DeployLimits) -> Self {
+        Extractor {
+            root,
+            limits,
+            entries: 0,
+            files: 0,
+            bytes: 0,
+            config_files: vec![],
+        }
+    }
+
+    /// Count an entry against the limit, before anything is written for it
+    fn add_entry(&mut self) -> Result<(), DeployError> {
+        if self.entries >= self.limits.max_entries {
+            return Err(DeployError::TooLarge);
+        }
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn add_dir(&mut self, name: &Path) -> Result<(), DeployError> {
+        fs::create_dir_all(self.root.join(get_entry_path(name)?))?;
+        Ok(())
+    }
+
+    fn add_file(&mut self, name: &Path, contents: &mut impl Read) -> Result<(), DeployError> {
+        let entry_path = get_entry_path(name)?;
+        if entry_path.as_os_str().is_empty() {
+            return Err(DeployError::Invalid(String::from("File with no name")));
+        }
+        let path = self.root.join(&entry_path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let remaining = self.limits.max_size - self.bytes;
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => {
+                    DeployError::Invalid(format!("Duplicate path {}", entry_path.display()))
+                }
+                _ => DeployError::Io(e),
+            })?;
+        let written = io::copy(&mut contents.take(remaining + 1), &mut file)?;
+        if written > remaining {
+            return Err(DeployError::TooLarge);
+        }
+
+        self.bytes += written;
+        self.files += 1;
+        if entry_path.file_name() == Some(CONFIG_FILENAME.as_ref()) {
+            self.config_files.push(entry_path);
+        }
+        Ok(())
+    }
+
+    fn extract_tar(&mut self, reader: impl Read) -> Result<(), DeployError> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            self.add_entry()?;
+            let name = entry.path()?.into_owned();
+            match entry.header().entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    self.add_file(&name, &mut entry)?
+                }
+                tar::EntryType::Directory => self.add_dir(&name)?,
+                // Extended headers only hold metadata
+                tar::EntryType::XGlobalHeader | tar::EntryType::XHeader => {}
+                _ => {
+                    return Err(DeployError::Invalid(format!(
+                        "Unsupported entry {}, only files and directories are allowed",
+                        name.display()
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn extract_zip(&mut self, archive: &[u8]) -> Result<(), DeployError> {
+        let mut archive = zip::ZipArchive::new(Cursor::new(archive))
+            .map_err(|e| DeployError::Invalid(e.to_string()))?;
+        for i in 0..archive.len() {
+            let mut file = archive
+                .by_index(i)
+                .map_err(|e| DeployError::Invalid(e.to_string()))?;
+            self.add_entry()?;
+            let name = PathBuf::from(file.name());
+            let is_symlink = file
+                .unix_mode()
+                .is_some_and(|mode| mode & 0o170000 == 0o120000);
+            if is_symlink {
+                return Err(DeployError::Invalid(format!(
+                    "Unsupported entry {}, only files and directories are allowed",
+                    name.display()
+                )));
+            } else if file.is_dir() {
+                self.add_dir(&name)?;
+            } else {
+                self.add_file(&name, &mut file)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn extract(&mut self, archive: &[u8]) -> Result<(), DeployError> {
+        match ArchiveFormat::detect(archive) {
+            Some(ArchiveFormat::TarGz) => self.extract_tar(flate2::read::GzDecoder::new(archive)),
+            Some(ArchiveFormat::TarZst) => self.extract_tar(zstd::Decoder::new(archive)?),
+            Some(ArchiveFormat::Zip) => self.extract_zip(archive),
+            None => Err(DeployError::Invalid(String::from(
+                "Archive must be a .tar.gz, .tar.zst or .zip",
+            ))),
+        }
+    }
+
+    /// Check every config in the archive can be parsed, as sites with invalid configs aren't served
+    fn validate_configs(&self) -> Result<(), DeployError> {
+        for config_file in self.config_files.iter() {
+            let contents = fs::read_to_string(self.root.join(config_file))?;
+            if let Err(e) = SiteConfig::parse(&contents) {
+                return Err(DeployError::Invalid(format!(
+                    "Invalid {}: {}",
+                    config_file.display(),
+                    e
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A unique path alongside the sites, hidden from discovery by its leading `.`
+pub fn get_temporary_path(sites_root: &Path, purpose: &str, hostname: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    sites_root.join(format!(
+        ".{}-{}-{}-{}",
+        purpose,
+        hostname,
+        std::process::id(),
+        nanos
+    ))
+}
+
+/// Atomically swap the contents of two paths
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let to_c_path = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (a, b) = (to_c_path(a)?, to_c_path(b)?);
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Without `renameat2`, the old content is moved aside first, so there's a brief gap where
+/// neither exists
+#[cfg(not(target_os = "linux"))]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    // Unique, as deploys of other sites may be exchanging alongside at the same time
+    let aside = match (b.parent(), b.file_name().and_then(|name| name.to_str())) {
+        (Some(parent), Some(name)) => get_temporary_path(parent, "exchange", name),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't exchange {}", b.display()),
+            ))
+        }
+    };
+    fs::rename(b, &aside)?;
+    fs::rename(a, b)?;
+    fs::rename(&aside, a)
+}
+
+/// Move `staging` to `target`, replacing anything already there. Returns whether `target`
+/// existed, in which case its old content is left at `staging`.
+fn swap_into_place(staging: &Path, target: &Path) -> io::Result<bool> {
+    if fs::symlink_metadata(target).is_ok() {
+        exchange(staging, target)?;
+        Ok(true)
+    } else {
+        fs::rename(staging, target)?;
+        Ok(false)
+    }
+}
+
+/// Extract an archive into a new directory, removing it again if anything goes wrong
+fn deploy_to_staging(
+    staging: &Path,
+    archive: &[u8],
+    limits: DeployLimits,
+) -> Result<(usize, u64), DeployError> {
+    fs::create_dir(staging)?;
+    let mut extractor = Extractor::new(staging, limits);
+    let result = extractor
+        .extract(archive)
+        .and_then(|_| extractor.validate_configs());
+    if let Err(e) = result {
+        let _ = fs::remove_dir_all(staging);
+        return Err(e);
+    }
+    Ok((extractor.files, extractor.bytes))
+}
+
+/// Extract an archive as a new release of `hostname`, and activate it atomically.
+///
+/// Sites which don't use releases yet are replaced by one which does.
+///
+/// This blocks, so should be run on a thread pool.
+pub fn deploy(
+    sites_root: &Path,
+    hostname: &str,
+    archive: &[u8],
+    limits: DeployLimits,
+) -> Result<Deployment, DeployError> {
+    let site_dir = sites_root.join(hostname);
+
+    if releases::is_release_layout(&site_dir) {
+        let releases_dir = site_dir.join(RELEASES_DIR);
+        let staging = get_temporary_path(&releases_dir, "deploy", hostname);
+        let (files, bytes) = deploy_to_staging(&staging, archive, limits)?;
+        // Claimed only once the archive is extracted, so failed deploys don't leave empty releases
+        let release = releases::create_release_dir(&site_dir)
+            .and_then(|release| {
+                fs::rename(&staging, releases_dir.join(&release))?;
+                Ok(release)
+            })
+            .inspect_err(|_| {
+                let _ = fs::remove_dir_all(&staging);
+            })?;
+        releases::activate(&site_dir, &release)?;
+
+        return Ok(Deployment {
+            hostname: hostname.to_owned(),
+            files,
+            bytes,
+            replaced: true,
+            release,
+        });
+    }
+
+    // Build the whole site directory aside, then swap it in
+    let staging = get_temporary_path(sites_root, "deploy", hostname);
+    let extracted = staging.join(RELEASES_DIR).join(".extracted");
+    fs::create_dir_all(staging.join(RELEASES_DIR))?;
+    let staged = deploy_to_staging(&extracted, archive, limits).and_then(|r| {
+        let release = releases::create_release_dir(&staging)?;
+        fs::rename(&extracted, staging.join(RELEASES_DIR).join(&release))?;
+        releases::activate(&staging, &release)?;
+        Ok((r, release))
+    });
+    let ((files, bytes), release) = match staged {
+        Ok(r) => r,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+
+    let replaced = swap_into_place(&staging, &site_dir)?;
+    if replaced {
+        fs::remove_dir_all(&staging)?;
+    }
+
+    Ok(Deployment {
+        hostname: hostname.to_owned(),
+        files,
+        bytes,
+        replaced,
+        release,
+    })
+}
+
+/// Remove the content for `hostname`, returning whether there was any.
+///
+/// It's moved aside before being deleted, so it disappears all at once.
+pub fn remove(sites_root: &Path, hostname: &str) -> io::Result<bool> {
+    let target = sites_root.join(hostname);
+    if fs::symlink_metadata(&target).is_err() {
+        return Ok(false);
+    }
+    let removing = get_temporary_path(sites_root, "remove", hostname);
+    fs::rename(&target, &removing)?;
+    fs::remove_dir_all(&removing)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use crate::test_utils::TestDir;
+    use std::io::Write;
+
+    const LIMITS: DeployLimits = DeployLimits {
+        max_size: 1024,
+        max_entries: 16,
+    };
+
+    pub fn get_tar(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_entry_type(tar::EntryType::Regular);
+            // `append_data` refuses unsafe paths, so set them directly
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    pub fn get_tar_gz(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        encoder.write_all(&get_tar(files)).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn get_zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, contents) in files {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn assert_only_site(root: &Path, hostname: &str) {
+        let entries: Vec<String> = fs::read_dir(root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(entries, vec![hostname.to_owned()]);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(
+            ArchiveFormat::detect(&get_tar_gz(&[])),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(&zstd::encode_all(&get_tar(&[])[..], 0).unwrap()),
+            Some(ArchiveFormat::TarZst)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(&get_zip(&[("a", "")])),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(ArchiveFormat::detect(&get_tar(&[])), None);
+    }
+
+    #[test]
+    fn test_entry_path() {
+        assert_eq!(
+            get_entry_path(Path::new("./a/b.html")).unwrap(),
+            PathBuf::from("a/b.html")
+        );
+        assert!(get_entry_path(Path::new("../a")).is_err());
+        assert!(get_entry_path(Path::new("a/../../b")).is_err());
+        assert!(get_entry_path(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_deploy_formats() {
+        let root = TestDir::new("deploy-formats");
+        let files = [("index.html", "hello"), ("docs/index.html", "docs")];
+
+        for archive in [
+            get_tar_gz(&files),
+            zstd::encode_all(&get_tar(&files)[..], 0).unwrap(),
+            get_zip(&files),
+        ] {
+            let deployment = deploy(&root, "example.com", &archive, LIMITS).unwrap();
+            assert_eq!(deployment.files, 2);
+            assert_eq!(deployment.bytes, 9);
+            assert_eq!(
+                fs::read_to_string(root.join("example.com/current/docs/index.html")).unwrap(),
+                "docs"
+            );
+        }
+        assert_only_site(&root, "example.com");
+    }
+
+    #[test]
+    fn test_deploy_replaces() {
+        let root = TestDir::new("deploy-replaces");
+        let first = deploy(
+            &root,
+            "example.com",
+            &get_tar_gz(&[("old.html", "old")]),
+            LIMITS,
+        )
+        .unwrap();
+        assert!(!first.replaced);
+
+        let second = deploy(
+            &root,
+            "example.com",
+            &get_tar_gz(&[("new.html", "new")]),
+            LIMITS,
+        )
+        .unwrap();
+        assert!(second.replaced);
+        assert!(!root.join("example.com/current/old.html").exists());
+        assert!(root.join("example.com/current/new.html").exists());
+
+        // The previous release is kept, so it can be rolled back to
+        let site_dir = root.join("example.com");
+        assert_eq!(
+            releases::list(&site_dir).unwrap().releases,
+            vec![first.release.clone(), second.release]
+        );
+        releases::rollback(&site_dir).unwrap();
+        assert!(root.join("example.com/current/old.html").exists());
+        assert_only_site(&root, "example.com");
+    }
+
+    #[test]
+    fn test_deploy_converts_to_releases() {
+        let root = TestDir::new("deploy-converts");
+        fs::create_dir(root.join("example.com")).unwrap();
+        fs::write(root.join("example.com/old.html"), "old").unwrap();
+
+        let deployment = deploy(
+            &root,
+            "example.com",
+            &get_tar_gz(&[("new.html", "new")]),
+            LIMITS,
+        )
+        .unwrap();
+        assert!(deployment.replaced);
+        assert!(!root.join("example.com/old.html").exists());
+        assert!(releases::is_release_layout(&root.join("example.com")));
+        assert_only_site(&root, "example.com");
+    }
+
+    #[test]
+    fn test_deploy_rejected() {
+        let root = TestDir::new("deploy-rejected");
+        deploy(
+            &root,
+            "example.com",
+            &get_tar_gz(&[("index.html", "live")]),
+            LIMITS,
+        )
+        .unwrap();
+
+        let traversal = get_tar_gz(&[("../escaped.html", "nope")]);
+        assert!(matches!(
+            deploy(&root, "example.com", &traversal, LIMITS),
+            Err(DeployError::Invalid(_))
+        ));
+        assert!(!root.join("escaped.html").exists());
+
+        let invalid_config = get_tar_gz(&[(CONFIG_FILENAME, "dir_index = 1")]);
+        assert!(matches!(
+            deploy(&root, "example.com", &invalid_config, LIMITS),
+            Err(DeployError::Invalid(_))
+        ));
+
+        let too_large = get_tar_gz(&[("a.html", "12345"), ("b.html", "67890")]);
+        assert!(matches!(
+            deploy(
+                &root,
+                "example.com",
+                &too_large,
+                DeployLimits {
+                    max_size: 8,
+                    ..LIMITS
+                }
+            ),
+            Err(DeployError::TooLarge)
+        ));
+
+        let too_many = get_tar_gz(&[("a.html", ""), ("b.html", ""), ("c.html", "")]);
+        assert!(matches!(
+            deploy(
+                &root,
+                "example.com",
+                &too_many,
+                DeployLimits {
+                    max_entries: 2,
+                    ..LIMITS
+                }
+            ),
+            Err(DeployError::TooLarge)
+        ));
+
+        assert!(matches!(
+            deploy(&root, "example.com", b"not an archive", LIMITS),
+            Err(DeployError::Invalid(_))
+        ));
+
+        // Failed deploys leave the live site alone, and clean up after themselves
+        assert_eq!(
+            fs::read_to_string(root.join("example.com/current/index.html")).unwrap(),
+            "live"
+        );
+        assert_eq!(
+            fs::read_dir(root.join("example.com").join(RELEASES_DIR))
+                .unwrap()
+                .count(),
+            1
+        );
+        assert_only_site(&root, "example.com");
+    }
+
+    #[test]
+    fn test_remove() {
+        let root = TestDir::new("deploy-remove");
+        deploy(
+            &root,
+            "example.com",
+            &get_tar_gz(&[("index.html", "")]),
+            LIMITS,
+        )
+        .unwrap();
+        assert!(remove(&root, "example.com").unwrap());
+        assert!(!remove(&root, "example.com").unwrap());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+    }
+}
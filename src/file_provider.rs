@@ -0,0 +1,109 @@
+use crate::files::write_atomic;
+use crate::format::Format;
+use crate::proxy::traefik::get_provider_config;
+use crate::settings::Settings;
+use log::{error, info};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Writes the provider configuration to a file watched by Traefik's file provider
+pub struct FileProvider {
+    path: PathBuf,
+    format: Format,
+    last_written: Option<String>,
+}
+
+impl FileProvider {
+    pub fn new(path: PathBuf) -> Option<Self> {
+        Some(FileProvider {
+            format: Format::from_path(&path)?,
+            path,
+            last_written: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the current configuration, if it changed since the last write.
+    ///
+    /// Returns whether the file was written.
+    pub async fn update(&mut self, settings: &Settings) -> io::Result<bool> {
+        let config = get_provider_config(settings).await?;
+        let contents = self.format.serialize(&config)?;
+
+        if self.last_written.as_ref() == Some(&contents) {
+            return Ok(false);
+        }
+
+        write_atomic(&self.path, &contents).await?;
+        self.last_written = Some(contents);
+        Ok(true)
+    }
+
+    /// Rewrite the file whenever the site registry changes
+    pub async fn run(mut self, settings: Settings) {
+        let mut generations = settings.registry.subscribe();
+        while generations.recv().await.is_some() {
+            match self.update(&settings).await {
+                Ok(true) => info!("Wrote provider config to {}", self.path.display()),
+                Ok(false) => {}
+                Err(e) => error!(
+                    "Failed to write provider config to {}: {}",
+                    self.path.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::get_test_settings;
+    use serde_json::Value;
+    use tokio::fs;
+
+    #[test]
+    fn test_requires_known_format() {
+        assert!(FileProvider::new(PathBuf::from("pages.yml")).is_some());
+        assert!(FileProvider::new(PathBuf::from("pages.toml")).is_some());
+        assert!(FileProvider::new(PathBuf::from("pages.txt")).is_none());
+        assert!(FileProvider::new(PathBuf::from("pages")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let settings = get_test_settings();
+        let path = std::env::temp_dir().join("traefik-pages-test-file-provider.yml");
+        let mut file_provider = FileProvider::new(path.clone()).unwrap();
+
+        assert!(file_provider.update(&settings).await.unwrap());
+        assert!(!file_provider.update(&settings).await.unwrap());
+
+        let contents = fs::read_to_string(&path).await.unwrap();
+        assert_eq!(
+            serde_yaml::from_str::<Value>(&contents).unwrap(),
+            get_provider_config(&settings).await.unwrap()
+        );
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_update_without_diagnostics() {
+        let mut settings = get_test_settings();
+        settings.default_middlewares = vec![String::from("compress")];
+        let path = std::env::temp_dir().join("traefik-pages-test-file-provider-diagnostics.json");
+        let mut file_provider = FileProvider::new(path.clone()).unwrap();
+
+        assert!(file_provider.update(&settings).await.unwrap());
+        let contents = fs::read_to_string(&path).await.unwrap();
+        let config = serde_json::from_str::<Value>(&contents).unwrap();
+        assert!(config.get("diagnostics").is_none());
+        assert!(config["http"]["routers"].get("localhost").is_some());
+        fs::remove_file(&path).await.unwrap();
+    }
+}
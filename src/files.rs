@@ -46,6 +46,25 @@ pub async fn handle_index(path: impl AsRef<Path>, index_file_name: &str) -> io::
     }
 }
 
+/// Write to a temporary file alongside the destination, then rename it into place,
+/// so readers never see a partially written file.
+pub async fn write_atomic(
+    path: impl AsRef<Path>,
+    contents: impl AsRef<[u8]> + Unpin,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    let file_name = path.file_name().and_then(|f| f.to_str()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            String::from("Invalid file name"),
+        )
+    })?;
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name));
+
+    fs::write(&temp_path, contents).await?;
+    fs::rename(&temp_path, path).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -88,6 +107,18 @@ mod tests {
         assert_eq!(join_err.unwrap_err().kind(), io::ErrorKind::NotFound);
     }
 
+    #[tokio::test]
+    async fn test_write_atomic() {
+        let path = std::env::temp_dir().join("traefik-pages-test-write-atomic.yml");
+        write_atomic(&path, "first").await.unwrap();
+        write_atomic(&path, "second").await.unwrap();
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "second");
+        assert!(!path
+            .with_file_name(".traefik-pages-test-write-atomic.yml.tmp")
+            .exists());
+        fs::remove_file(&path).await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_resolve_fail_traversal() {
         let resolve_err = safe_join(get_example_dir(), "../../Cargo.toml")
@@ -0,0 +1,105 @@
+use serde_json::Value;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+fn to_io_error(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Format> {
+        Format::from_name(path.as_ref().extension()?.to_str()?)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Yaml => "application/yaml",
+            Format::Toml => "application/toml",
+        }
+    }
+
+    pub fn serialize(&self, value: &Value) -> io::Result<String> {
+        match self {
+            Format::Json => serde_json::to_string(value).map_err(to_io_error),
+            Format::Yaml => serde_yaml::to_string(value).map_err(to_io_error),
+            Format::Toml => {
+                // Converting to a `toml::Value` first ensures tables are written after plain values
+                let toml_value = toml::Value::try_from(value).map_err(to_io_error)?;
+                toml::to_string(&toml_value).map_err(to_io_error)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn get_test_value() -> Value {
+        json!({
+            "http": {
+                "routers": {
+                    "localhost": {
+                        "rule": "Host(`localhost`)",
+                        "tls": {
+                            "certResolver": "le"
+                        },
+                        "service": "traefik-pages@docker"
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Format::from_name("json"), Some(Format::Json));
+        assert_eq!(Format::from_name("YAML"), Some(Format::Yaml));
+        assert_eq!(Format::from_name("yml"), Some(Format::Yaml));
+        assert_eq!(Format::from_name("toml"), Some(Format::Toml));
+        assert_eq!(Format::from_name("xml"), None);
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(
+            Format::from_path("/etc/traefik/dynamic/pages.yml"),
+            Some(Format::Yaml)
+        );
+        assert_eq!(Format::from_path("pages.toml"), Some(Format::Toml));
+        assert_eq!(Format::from_path("pages"), None);
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let value = get_test_value();
+
+        let yaml = Format::Yaml.serialize(&value).unwrap();
+        assert_eq!(serde_yaml::from_str::<Value>(&yaml).unwrap(), value);
+
+        let toml = Format::Toml.serialize(&value).unwrap();
+        assert_eq!(toml::from_str::<Value>(&toml).unwrap(), value);
+
+        let json = Format::Json.serialize(&value).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value);
+    }
+}
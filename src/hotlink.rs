@@ -0,0 +1,180 @@
+use actix_web::http::header::{HeaderMap, ORIGIN, REFERER};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::de::{Deserialize, Deserializer, Error};
+use serde::ser::{Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use url::Url;
+
+/// Glob patterns, compiled once when the config is read
+#[derive(Clone)]
+pub struct PathGlobs {
+    patterns: Vec<String>,
+    set: GlobSet,
+}
+
+impl PathGlobs {
+    pub fn new(patterns: Vec<String>) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns.iter() {
+            builder.add(Glob::new(pattern.trim_start_matches('/'))?);
+        }
+        Ok(PathGlobs {
+            set: builder.build()?,
+            patterns,
+        })
+    }
+
+    pub fn is_match(&self, site_path: &str) -> bool {
+        self.set.is_match(site_path)
+    }
+}
+
+impl fmt::Debug for PathGlobs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.patterns.fmt(f)
+    }
+}
+
+impl PartialEq for PathGlobs {
+    fn eq(&self, other: &Self) -> bool {
+        self.patterns == other.patterns
+    }
+}
+
+impl<'de> Deserialize<'de> for PathGlobs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        PathGlobs::new(Vec::<String>::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for PathGlobs {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.patterns.serialize(serializer)
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Only serve some paths when they're linked to from allowed hosts
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct HotlinkProtection {
+    /// Relative to the site, e.g. `**/*.jpg` or `downloads/**`
+    pub paths: PathGlobs,
+
+    /// Besides the site's own hostname. `*.example.com` allows any subdomain.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+
+    /// Requests without a `Referer` or `Origin`, such as links opened directly, are allowed
+    #[serde(default = "default_true")]
+    pub allow_missing_referer: bool,
+
+    /// Served in place of blocked files, relative to the site. Otherwise, they get a `403`.
+    pub replacement: Option<String>,
+}
+
+/// Hostnames are case insensitive, for wildcards too
+fn is_host_allowed(host: &str, allowed: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    let allowed = allowed.to_ascii_lowercase();
+    match allowed.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.')),
+        None => host == allowed,
+    }
+}
+
+impl HotlinkProtection {
+    /// Whether a request may be served, going by where it was linked from
+    pub fn is_allowed(&self, site_path: &str, hostname: &str, headers: &HeaderMap) -> bool {
+        if !self.paths.is_match(site_path) {
+            return true;
+        }
+        let linked_from = match headers.get(ORIGIN).or_else(|| headers.get(REFERER)) {
+            Some(value) => value,
+            None => return self.allow_missing_referer,
+        };
+        let url = match linked_from.to_str().ok().and_then(|v| Url::parse(v).ok()) {
+            Some(u) => u,
+            None => return false,
+        };
+        let host = url.host_str().unwrap_or_default();
+        std::iter::once(hostname)
+            .chain(self.allowed_hosts.iter().map(String::as_str))
+            .any(|allowed| is_host_allowed(host, allowed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::HeaderValue;
+
+    fn get_protection() -> HotlinkProtection {
+        toml::from_str(
+            r#"
+            paths = ["**/*.jpg", "/downloads/**"]
+            allowed_hosts = ["partner.com", "*.example.org"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn get_headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            actix_web::http::HeaderName::from_static(name),
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_is_host_allowed() {
+        assert!(is_host_allowed("example.com", "example.com"));
+        assert!(is_host_allowed("cdn.example.com", "*.example.com"));
+        assert!(!is_host_allowed("example.com", "*.example.com"));
+        assert!(!is_host_allowed("badexample.com", "*.example.com"));
+        assert!(is_host_allowed("Example.com", "example.COM"));
+        assert!(is_host_allowed("CDN.example.com", "*.Example.com"));
+        assert!(!is_host_allowed("badEXAMPLE.com", "*.example.com"));
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let protection = get_protection();
+        let own = get_headers("referer", "https://example.com/gallery/");
+        let partner = get_headers("referer", "https://partner.com/");
+        let subdomain = get_headers("origin", "https://www.example.org");
+        let other = get_headers("referer", "https://thief.com/page.html");
+
+        assert!(protection.is_allowed("index.html", "example.com", &other));
+        for headers in [&own, &partner, &subdomain, &HeaderMap::new()] {
+            assert!(protection.is_allowed("images/cat.jpg", "example.com", headers));
+        }
+        assert!(!protection.is_allowed("images/cat.jpg", "example.com", &other));
+        assert!(!protection.is_allowed("downloads/big.iso", "example.com", &other));
+        assert!(!protection.is_allowed(
+            "downloads/big.iso",
+            "example.com",
+            &get_headers("referer", "nonsense")
+        ));
+    }
+
+    #[test]
+    fn test_missing_referer() {
+        let mut protection = get_protection();
+        protection.allow_missing_referer = false;
+        assert!(!protection.is_allowed("cat.jpg", "example.com", &HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_invalid_glob() {
+        assert!(toml::from_str::<HotlinkProtection>("paths = ['a[']").is_err());
+    }
+}
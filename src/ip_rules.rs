@@ -0,0 +1,90 @@
+use ipnet::IpNet;
+use serde::de::{Deserialize, Deserializer, Error};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A CIDR range, or a single address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpRange(IpNet);
+
+impl IpRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .parse::<IpNet>()
+            .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+            .map(IpRange)
+            .map_err(|_| format!("Invalid IP range {}", value))
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for IpRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Whether an address is allowed by a pair of allow and deny lists.
+///
+/// Denying wins. An empty allow list allows everything which isn't denied.
+pub fn is_ip_allowed(ip: &IpAddr, allow: &[IpRange], deny: &[IpRange]) -> bool {
+    !deny.iter().any(|range| range.contains(ip))
+        && (allow.is_empty() || allow.iter().any(|range| range.contains(ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_ranges(values: &[&str]) -> Vec<IpRange> {
+        values.iter().map(|v| v.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("10.0.0.0/8".parse::<IpRange>().is_ok());
+        assert!("::1".parse::<IpRange>().is_ok());
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("office".parse::<IpRange>().is_err());
+        assert_eq!(
+            "192.0.2.1".parse::<IpRange>().unwrap().to_string(),
+            "192.0.2.1/32"
+        );
+    }
+
+    #[test]
+    fn test_is_ip_allowed() {
+        let ip: IpAddr = "10.8.1.1".parse().unwrap();
+        assert!(is_ip_allowed(&ip, &[], &[]));
+        assert!(is_ip_allowed(&ip, &get_ranges(&["10.8.0.0/16"]), &[]));
+        assert!(!is_ip_allowed(&ip, &get_ranges(&["192.0.2.0/24"]), &[]));
+        assert!(!is_ip_allowed(
+            &ip,
+            &get_ranges(&["10.8.0.0/16"]),
+            &get_ranges(&["10.8.1.1"])
+        ));
+    }
+}
@@ -0,0 +1,399 @@
+use crate::proxy::traefik::{
+    get_middleware, get_router_middlewares, get_router_priority, get_router_rule,
+};
+use crate::proxy::{get_site_routes, SiteRoute};
+use crate::settings::Settings;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::io;
+
+const TRAEFIK_API_VERSION: &str = "traefik.io/v1alpha1";
+const INGRESS_API_VERSION: &str = "networking.k8s.io/v1";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ManifestKind {
+    /// Traefik's `IngressRoute` CRD
+    IngressRoute,
+    /// The standard `Ingress` resource, configured through Traefik's annotations
+    Ingress,
+}
+
+impl ManifestKind {
+    pub fn from_name(name: &str) -> Option<ManifestKind> {
+        match name.to_lowercase().as_str() {
+            "ingressroute" => Some(ManifestKind::IngressRoute),
+            "ingress" => Some(ManifestKind::Ingress),
+            _ => None,
+        }
+    }
+}
+
+fn is_valid_resource_name(name: &str) -> bool {
+    name.len() <= 63
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+/// Kubernetes names are stricter than router names, so add a hash to keep them unique when
+/// the router name has to be changed.
+fn get_resource_name(router_name: &str) -> String {
+    if is_valid_resource_name(router_name) {
+        return router_name.to_owned();
+    }
+    let sanitised: String = router_name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(50)
+        .collect();
+    let hash = format!("{:x}", Sha256::digest(router_name.as_bytes()));
+    format!("{}-{}", sanitised.trim_matches('-'), &hash[..8])
+}
+
+/// References to other Traefik providers (`name@provider`) can't be made from Kubernetes
+/// resources, so are rejected rather than producing resources Kubernetes or Traefik would refuse
+fn check_local_reference(reference: &str, referenced_by: &str) -> io::Result<()> {
+    if reference.contains('@') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} refers to {}, from another Traefik provider, which Kubernetes resources can't",
+                referenced_by, reference
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn get_metadata(name: &str, settings: &Settings) -> Value {
+    json!({
+        "name": name,
+        "namespace": &settings.kubernetes_namespace
+    })
+}
+
+/// Middlewares as `Middleware` resources, with chains referring to other resources
+fn get_middleware_manifests(settings: &Settings) -> io::Result<Vec<Value>> {
+    let middlewares = get_middleware(settings);
+    middlewares
+        .as_object()
+        .unwrap()
+        .iter()
+        .map(|(name, spec)| {
+            let mut spec = spec.clone();
+            if let Some(chain) = spec
+                .pointer_mut("/chain/middlewares")
+                .and_then(Value::as_array_mut)
+            {
+                for middleware in chain.iter_mut() {
+                    check_local_reference(middleware.as_str().unwrap_or_default(), name)?;
+                    *middleware = json!({ "name": middleware });
+                }
+            }
+            Ok(json!({
+                "apiVersion": TRAEFIK_API_VERSION,
+                "kind": "Middleware",
+                "metadata": get_metadata(name, settings),
+                "spec": spec
+            }))
+        })
+        .collect()
+}
+
+fn get_ingress_route(route: &SiteRoute, settings: &Settings) -> io::Result<Value> {
+    let service = match &route.service_override {
+        Some(service) => {
+            check_local_reference(service, &route.hostname)?;
+            json!({ "name": service, "kind": "TraefikService" })
+        }
+        None => json!({
+            "name": &settings.kubernetes_service,
+            "port": settings.kubernetes_service_port
+        }),
+    };
+
+    let rule = get_router_rule(route);
+    let mut route_spec = json!({
+        "kind": "Rule",
+        "match": &rule,
+        "services": [service]
+    });
+    if let Some(priority) = get_router_priority(route, &rule) {
+        route_spec["priority"] = json!(priority);
+    }
+    let middlewares = get_router_middlewares(route);
+    if !middlewares.is_empty() {
+        route_spec["middlewares"] = middlewares.iter().map(|m| json!({ "name": m })).collect();
+    }
+
+    let mut spec = json!({ "routes": [route_spec] });
+    if let Some(cert_resolver) = &settings.traefik_cert_resolver {
+        spec["tls"] = json!({ "certResolver": cert_resolver });
+    }
+
+    Ok(json!({
+        "apiVersion": TRAEFIK_API_VERSION,
+        "kind": "IngressRoute",
+        "metadata": get_metadata(&get_resource_name(&route.name), settings),
+        "spec": spec
+    }))
+}
+
+fn get_ingress(route: &SiteRoute, settings: &Settings) -> io::Result<Value> {
+    // An Ingress can only route to Kubernetes services, so a `TraefikService` needs an IngressRoute
+    if let Some(service) = &route.service_override {
+        check_local_reference(service, &route.hostname)?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} routes to the TraefikService {}, which only IngressRoutes can",
+                route.hostname, service
+            ),
+        ));
+    }
+
+    let mut annotations = Map::new();
+    let middlewares = get_router_middlewares(route);
+    if !middlewares.is_empty() {
+        // Middlewares from the CRD provider are namespaced
+        let middlewares: Vec<String> = middlewares
+            .iter()
+            .map(|m| format!("{}-{}@kubernetescrd", settings.kubernetes_namespace, m))
+            .collect();
+        annotations.insert(
+            String::from("traefik.ingress.kubernetes.io/router.middlewares"),
+            json!(middlewares.join(",")),
+        );
+    }
+    if let Some(priority) = get_router_priority(route, &get_router_rule(route)) {
+        annotations.insert(
+            String::from("traefik.ingress.kubernetes.io/router.priority"),
+            json!(priority.to_string()),
+        );
+    }
+    if let Some(cert_resolver) = &settings.traefik_cert_resolver {
+        annotations.insert(
+            String::from("traefik.ingress.kubernetes.io/router.tls"),
+            json!("true"),
+        );
+        annotations.insert(
+            String::from("traefik.ingress.kubernetes.io/router.tls.certresolver"),
+            json!(cert_resolver),
+        );
+    }
+
+    let path = match &route.path_prefix {
+        Some(prefix) => format!("/{}", prefix),
+        None => String::from("/"),
+    };
+
+    let mut metadata = get_metadata(&get_resource_name(&route.name), settings);
+    metadata["annotations"] = Value::Object(annotations);
+
+    Ok(json!({
+        "apiVersion": INGRESS_API_VERSION,
+        "kind": "Ingress",
+        "metadata": metadata,
+        "spec": {
+            "rules": [{
+                "host": &route.hostname,
+                "http": {
+                    "paths": [{
+                        "path": path,
+                        "pathType": "Prefix",
+                        "backend": {
+                            "service": {
+                                "name": &settings.kubernetes_service,
+                                "port": {
+                                    "number": settings.kubernetes_service_port
+                                }
+                            }
+                        }
+                    }]
+                }
+            }]
+        }
+    }))
+}
+
+pub async fn get_manifests(settings: &Settings, kind: ManifestKind) -> io::Result<Vec<Value>> {
+    let mut manifests = get_middleware_manifests(settings)?;
+    for route in get_site_routes(settings).await? {
+        manifests.push(match kind {
+            ManifestKind::IngressRoute => get_ingress_route(&route, settings)?,
+            ManifestKind::Ingress => get_ingress(&route, settings)?,
+        });
+    }
+    Ok(manifests)
+}
+
+/// Render manifests as a multi-document YAML stream
+pub fn to_yaml(manifests: &[Value]) -> io::Result<String> {
+    let mut yaml = String::new();
+    for manifest in manifests {
+        // Each document starts with its own `---` separator
+        yaml.push_str(
+            &serde_yaml::to_string(manifest)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        );
+        if !yaml.ends_with('\n') {
+            yaml.push('\n');
+        }
+    }
+    Ok(yaml)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::get_test_settings;
+
+    #[test]
+    fn test_manifest_kind_from_name() {
+        assert_eq!(
+            ManifestKind::from_name("IngressRoute"),
+            Some(ManifestKind::IngressRoute)
+        );
+        assert_eq!(
+            ManifestKind::from_name("ingress"),
+            Some(ManifestKind::Ingress)
+        );
+        assert_eq!(ManifestKind::from_name("gateway"), None);
+    }
+
+    #[test]
+    fn test_resource_name() {
+        assert_eq!(get_resource_name("site1-localhost"), "site1-localhost");
+        assert_eq!(
+            get_resource_name("localhost__nested"),
+            format!(
+                "localhost--nested-{}",
+                &format!("{:x}", Sha256::digest(b"localhost__nested"))[..8]
+            )
+        );
+        assert_ne!(
+            get_resource_name("a_2db-example-com"),
+            get_resource_name("a-b_2dexample-com")
+        );
+        assert!(is_valid_resource_name(&get_resource_name(&"A_".repeat(60))));
+    }
+
+    #[test]
+    fn test_middleware_manifests() {
+        let settings = get_test_settings();
+        let manifests = get_middleware_manifests(&settings).unwrap();
+        assert_eq!(manifests.len(), 2);
+        assert_eq!(manifests[1]["spec"], json!({"retry": {"attempts": 4}}));
+        assert_eq!(
+            manifests[0],
+            json!({
+                "apiVersion": TRAEFIK_API_VERSION,
+                "kind": "Middleware",
+                "metadata": {"name": "tp-default", "namespace": "default"},
+                "spec": {"chain": {"middlewares": [{"name": "tp-retry"}]}}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ingress_route() {
+        let settings = get_test_settings();
+        let site = settings.site_from_hostname("localhost", "").await.unwrap();
+        assert_eq!(
+            get_ingress_route(&SiteRoute::new(&site), &settings).unwrap(),
+            json!({
+                "apiVersion": TRAEFIK_API_VERSION,
+                "kind": "IngressRoute",
+                "metadata": {"name": "localhost", "namespace": "default"},
+                "spec": {
+                    "routes": [{
+                        "kind": "Rule",
+                        "match": "Host(`localhost`)",
+                        "middlewares": [{"name": "tp-default"}],
+                        "services": [{"name": "traefik-pages", "port": 5000}]
+                    }],
+                    "tls": {"certResolver": "le"}
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ingress() {
+        let settings = get_test_settings();
+        let site = settings
+            .site_from_hostname("localhost", "nested")
+            .await
+            .unwrap();
+        let route = SiteRoute::new(&site);
+        let ingress = get_ingress(&route, &settings).unwrap();
+        assert_eq!(
+            ingress["metadata"]["annotations"],
+            json!({
+                "traefik.ingress.kubernetes.io/router.middlewares": "default-tp-default@kubernetescrd",
+                "traefik.ingress.kubernetes.io/router.priority": get_router_priority(&route, &get_router_rule(&route)).unwrap().to_string(),
+                "traefik.ingress.kubernetes.io/router.tls": "true",
+                "traefik.ingress.kubernetes.io/router.tls.certresolver": "le"
+            })
+        );
+        assert_eq!(
+            ingress["spec"]["rules"][0],
+            json!({
+                "host": "localhost",
+                "http": {
+                    "paths": [{
+                        "path": "/nested",
+                        "pathType": "Prefix",
+                        "backend": {
+                            "service": {"name": "traefik-pages", "port": {"number": 5000}}
+                        }
+                    }]
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_other_provider_references() {
+        let mut settings = get_test_settings();
+        settings.default_middlewares = vec![String::from("compress@file")];
+        assert!(get_middleware_manifests(&settings).is_err());
+        for kind in [ManifestKind::IngressRoute, ManifestKind::Ingress] {
+            let error = get_manifests(&settings, kind).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        let settings = get_test_settings();
+        let site = settings.site_from_hostname("localhost", "").await.unwrap();
+        let mut route = SiteRoute::new(&site);
+        route.service_override = Some(String::from("traefik-pages@docker"));
+        assert!(get_ingress_route(&route, &settings).is_err());
+        assert!(get_ingress(&route, &settings).is_err());
+
+        // Only IngressRoutes can route to a TraefikService
+        route.service_override = Some(String::from("canary"));
+        assert_eq!(
+            get_ingress_route(&route, &settings).unwrap()["spec"]["routes"][0]["services"],
+            json!([{"name": "canary", "kind": "TraefikService"}])
+        );
+        assert!(get_ingress(&route, &settings).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_to_yaml() {
+        let settings = get_test_settings();
+        let manifests = get_manifests(&settings, ManifestKind::IngressRoute)
+            .await
+            .unwrap();
+        assert_eq!(manifests.len(), 2 + 5);
+
+        let yaml = to_yaml(&manifests).unwrap();
+        assert_eq!(yaml.matches("---").count(), manifests.len());
+        assert_eq!(yaml.matches("kind: IngressRoute").count(), 5);
+        assert_eq!(yaml.matches("kind: Middleware").count(), 2);
+    }
+}
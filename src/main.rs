@@ -1,17 +1,41 @@
-use actix_web::middleware::{Compress, Logger};
+use actix_web::middleware::Compress;
 use actix_web::{App, HttpServer};
 use env_logger::Env;
+use log::{info, warn};
 use std::env;
 use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+mod access_log;
+mod access_policy;
 mod app;
 mod auth;
+mod check;
+mod client_ip;
+mod commands;
+mod deploy;
+mod file_provider;
 mod files;
+mod format;
+mod hotlink;
+mod ip_rules;
+mod kubernetes;
+mod metrics;
+mod proxy;
+mod rate_limit;
+mod registry;
+mod releases;
+mod request_id;
 mod routes;
 mod settings;
 mod site;
 mod site_config;
+mod throttle;
+mod url_signing;
 mod utils;
+mod validation;
 
 #[cfg(test)]
 mod test_utils;
@@ -26,39 +50,183 @@ fn get_sites_root() -> PathBuf {
     }
 }
 
-fn get_logger() -> Logger {
-    let logger = Logger::new(r#"%{r}a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#);
+fn get_well_known_dir() -> Option<PathBuf> {
+    let well_known_dir = env::var("WELL_KNOWN_DIR").ok()?;
+    match PathBuf::from(well_known_dir).canonicalize() {
+        Ok(p) if p.is_dir() => Some(p),
+        _ => utils::log_error_and_quit("Invalid well-known directory."),
+    }
+}
 
-    if env::var("LOG_INTERNAL").is_ok() {
-        return logger;
+fn get_traefik_service(traefik_service_urls: &[String]) -> String {
+    if traefik_service_urls.is_empty() {
+        return utils::get_env_or_default("TRAEFIK_SERVICE", None);
     }
 
-    logger.exclude_regex(format!(r#"\{}"#, routes::INTERNAL_ROUTE_PREFIX))
+    // When traefik-pages defines its own service, it belongs to this provider
+    let traefik_service = utils::get_env_or_default("TRAEFIK_SERVICE", Some("traefik-pages"));
+    if traefik_service.contains('@') {
+        utils::log_error_and_quit(
+            "TRAEFIK_SERVICE must not include a provider when TRAEFIK_SERVICE_URLS is set.",
+        );
+    }
+    traefik_service
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+fn get_upstream_urls(traefik_service_urls: &[String]) -> Vec<String> {
+    let upstream_urls = utils::get_upstream_urls(traefik_service_urls);
+    if let Some(url) = upstream_urls
+        .iter()
+        .find(|url| proxy::Upstream::from_url(url).is_none())
+    {
+        utils::log_error_and_quit(&format!("Invalid upstream URL {}.", url));
+    }
+    upstream_urls
+}
 
-    let settings = settings::Settings {
-        sites_root: get_sites_root(),
-        traefik_service: utils::get_env_or_default("TRAEFIK_SERVICE", None),
-        traefik_cert_resolver: env::var("TRAEFIK_CERT_RESOLVER").ok(),
-        auth_password: utils::get_env_or_default("AUTH_PASSWORD", None),
-        deny_prefixes: utils::get_env_or_default("DENY_PREFIXES", Some(""))
-            .split(',')
-            .map(String::from)
-            .filter(|s| !s.is_empty())
-            .collect(),
+fn get_api_tokens() -> Vec<auth::ApiToken> {
+    let mut tokens = vec![];
+
+    // The original shared password is kept working as an all-powerful token
+    if let Ok(password) = env::var("AUTH_PASSWORD") {
+        tokens.push(auth::ApiToken::new(
+            "default",
+            &password,
+            vec![auth::TokenScope::Admin],
+        ));
+    }
+
+    if let Ok(tokens_file) = env::var("AUTH_TOKENS_FILE") {
+        match auth::load_tokens_file(&PathBuf::from(&tokens_file)) {
+            Ok(t) => tokens.extend(t),
+            Err(e) => utils::log_error_and_quit(&format!(
+                "Failed to load tokens from {}: {}",
+                tokens_file, e
+            )),
+        }
+    }
+
+    for token in utils::get_env_list("AUTH_TOKENS") {
+        match auth::parse_token(&token) {
+            Ok(t) => tokens.push(t),
+            Err(e) => utils::log_error_and_quit(&e),
+        }
+    }
+
+    tokens
+}
+
+fn get_trusted_proxies() -> client_ip::TrustedProxies {
+    match client_ip::TrustedProxies::parse(&utils::get_env_list("TRUSTED_PROXIES")) {
+        Ok(t) => t,
+        Err(e) => utils::log_error_and_quit(&e),
+    }
+}
+
+fn get_access_log() -> access_log::AccessLog {
+    let format_name = utils::get_env_or_default("ACCESS_LOG_FORMAT", Some("text"));
+    let format = match access_log::AccessLogFormat::from_name(&format_name) {
+        Some(f) => f,
+        None => utils::log_error_and_quit("ACCESS_LOG_FORMAT must be text or json."),
     };
+    let filter = access_log::AccessLogFilter {
+        log_internal: env::var("LOG_INTERNAL").is_ok(),
+        log_health_checks: env::var("LOG_HEALTH_CHECKS").is_ok(),
+        sample_rate: utils::get_access_log_sample_rate(),
+    };
+    access_log::AccessLog::new(
+        format,
+        filter,
+        env::var("ACCESS_LOG_DIR").ok().map(PathBuf::from),
+    )
+}
+
+fn get_settings(registry: Arc<registry::SiteRegistry>) -> settings::Settings {
+    let traefik_service_urls = utils::get_env_list("TRAEFIK_SERVICE_URLS");
+    let (kubernetes_service, kubernetes_service_port) = utils::get_kubernetes_service();
+
+    settings::Settings {
+        traefik_service: get_traefik_service(&traefik_service_urls),
+        upstream_urls: get_upstream_urls(&traefik_service_urls),
+        traefik_service_urls,
+        traefik_cert_resolver: env::var("TRAEFIK_CERT_RESOLVER").ok(),
+        api_tokens: get_api_tokens(),
+        trusted_proxies: get_trusted_proxies(),
+        auth_throttle: Arc::new(throttle::FailureThrottle::new(
+            utils::get_auth_max_failures(),
+            Duration::from_secs(utils::get_auth_failure_window()),
+        )),
+        deny_prefixes: utils::get_env_list("DENY_PREFIXES"),
+        retry_attempts: utils::get_retry_attempts(),
+        retry_initial_interval: env::var("RETRY_INITIAL_INTERVAL").ok(),
+        default_middlewares: utils::get_env_list("DEFAULT_MIDDLEWARES"),
+        kubernetes_namespace: utils::get_env_or_default("KUBERNETES_NAMESPACE", Some("default")),
+        kubernetes_service,
+        kubernetes_service_port,
+        deploy_max_size: utils::get_size_mb("DEPLOY_MAX_SIZE", "100") as usize,
+        deploy_max_extracted_size: utils::get_size_mb("DEPLOY_MAX_EXTRACTED_SIZE", "1024"),
+        deploy_max_entries: utils::get_deploy_max_entries(),
+        release_retention: utils::get_release_retention(),
+        site_locks: Arc::new(releases::SiteLocks::default()),
+        ready_max_config_errors: utils::get_ready_max_config_errors(),
+        rate_limit: utils::get_rate_limit(),
+        rate_limiter: Arc::new(rate_limit::RateLimiter::default()),
+        url_signer: env::var("URL_SIGNING_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+            .map(|key| url_signing::UrlSigner::new(key.as_bytes())),
+        well_known_dir: get_well_known_dir(),
+        registry,
+        metrics: Arc::new(metrics::Metrics::default()),
+        access_log: Arc::new(get_access_log()),
+        started: Instant::now(),
+    }
+}
+
+async fn serve(settings: settings::Settings) -> std::io::Result<()> {
+    if settings.api_tokens.is_empty() {
+        warn!("No API tokens configured, so the internal API can't be used.");
+    } else {
+        let names: Vec<&str> = settings
+            .api_tokens
+            .iter()
+            .map(|t| t.name.as_str())
+            .collect();
+        info!("Accepting API tokens: {}", names.join(", "));
+    }
+
+    if let Err(e) = settings.registry.clone().watch() {
+        warn!(
+            "Failed to watch sites root, relying on periodic rescans: {}",
+            e
+        );
+    }
+    tokio::spawn(
+        settings
+            .registry
+            .clone()
+            .rescan_periodically(Duration::from_secs(utils::get_rescan_interval())),
+    );
+
+    if let Ok(provider_file) = env::var("PROVIDER_FILE") {
+        let file_provider = match file_provider::FileProvider::new(PathBuf::from(provider_file)) {
+            Some(f) => f,
+            None => utils::log_error_and_quit("Provider file must be JSON, YAML or TOML."),
+        };
+        info!(
+            "Writing provider config to {}",
+            file_provider.path().display()
+        );
+        tokio::spawn(file_provider.run(settings.clone()));
+    }
 
     let local = tokio::task::LocalSet::new();
     let sys = actix_web::rt::System::run_in_tokio("server", &local);
+    let access_log = settings.access_log.clone();
 
     HttpServer::new(move || {
         App::new()
             .configure(|cfg| app::configure_app(cfg, settings.clone()))
-            .wrap(get_logger())
             .wrap(Compress::default())
     })
     .workers(utils::get_workers())
@@ -66,7 +234,35 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await?;
 
+    // Don't lose lines still waiting to be written
+    access_log.flush();
     sys.await?;
 
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    let sites_root = get_sites_root();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("check") {
+        exit(commands::check(&sites_root).await);
+    }
+
+    let registry = Arc::new(registry::SiteRegistry::new(sites_root));
+
+    if let Err(e) = registry.rescan().await {
+        utils::log_error_and_quit(&format!("Failed to discover sites: {}", e));
+    }
+
+    let settings = get_settings(registry);
+
+    match args.split_first() {
+        Some((command, command_args)) => {
+            exit(commands::run(command, command_args, &settings).await)
+        }
+        None => serve(settings).await,
+    }
+}
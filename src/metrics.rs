@@ -0,0 +1,295 @@
+use actix_web::http::StatusCode;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the request duration histogram's buckets, in seconds
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The site a request was served by, attached to the request so it can be used as a label
+pub struct SiteLabel(pub String);
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+fn get_status_class(status: StatusCode) -> String {
+    format!("{}xx", status.as_u16() / 100)
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_site_counter(out: &mut String, name: &str, help: &str, values: &BTreeMap<String, u64>) {
+    write_header(out, name, "counter", help);
+    for (site, value) in values {
+        let _ = writeln!(out, "{}{{site=\"{}\"}} {}", name, escape_label(site), value);
+    }
+}
+
+/// Counters for everything Traefik can't see, in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    /// Keyed by site and status class
+    requests: Mutex<BTreeMap<(String, String), Histogram>>,
+    bytes_sent: Mutex<BTreeMap<String, u64>>,
+    not_found: Mutex<BTreeMap<String, u64>>,
+    denied: Mutex<BTreeMap<String, u64>>,
+    rate_limited: Mutex<BTreeMap<String, u64>>,
+    provider_polls: AtomicU64,
+}
+
+fn increment(counters: &Mutex<BTreeMap<String, u64>>, site: &str, amount: u64) {
+    *counters.lock().unwrap().entry(site.to_owned()).or_default() += amount;
+}
+
+impl Metrics {
+    /// Record a response. Requests not served by a site have an empty `site`.
+    pub fn record_request(&self, site: &str, status: StatusCode, bytes: u64, duration: Duration) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry((site.to_owned(), get_status_class(status)))
+            .or_default()
+            .observe(duration.as_secs_f64());
+        increment(&self.bytes_sent, site, bytes);
+        if status == StatusCode::NOT_FOUND {
+            increment(&self.not_found, site, 1);
+        }
+    }
+
+    /// Record a request refused by a deny prefix or access rule
+    pub fn record_denied(&self, site: &str) {
+        increment(&self.denied, site, 1);
+    }
+
+    /// Record a request refused by the rate limit
+    pub fn record_rate_limited(&self, site: &str) {
+        increment(&self.rate_limited, site, 1);
+    }
+
+    pub fn record_provider_poll(&self) {
+        self.provider_polls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(
+        &self,
+        sites: usize,
+        config_errors: usize,
+        discovery: Option<Duration>,
+        rate_limit_clients: usize,
+    ) -> String {
+        let mut out = String::new();
+        let requests = self.requests.lock().unwrap();
+
+        write_header(
+            &mut out,
+            "traefik_pages_requests_total",
+            "counter",
+            "Requests handled, by site and status class.",
+        );
+        for ((site, status), histogram) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "traefik_pages_requests_total{{site=\"{}\",status=\"{}\"}} {}",
+                escape_label(site),
+                status,
+                histogram.count
+            );
+        }
+
+        write_header(
+            &mut out,
+            "traefik_pages_request_duration_seconds",
+            "histogram",
+            "Time taken to handle requests, by site and status class.",
+        );
+        for ((site, status), histogram) in requests.iter() {
+            let labels = format!("site=\"{}\",status=\"{}\"", escape_label(site), status);
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                let _ = writeln!(
+                    out,
+                    "traefik_pages_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "traefik_pages_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "traefik_pages_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "traefik_pages_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+        drop(requests);
+
+        write_site_counter(
+            &mut out,
+            "traefik_pages_response_bytes_total",
+            "Response body bytes sent, by site.",
+            &self.bytes_sent.lock().unwrap(),
+        );
+        write_site_counter(
+            &mut out,
+            "traefik_pages_not_found_total",
+            "Requests answered with a 404, by site.",
+            &self.not_found.lock().unwrap(),
+        );
+        write_site_counter(
+            &mut out,
+            "traefik_pages_denied_total",
+            "Requests refused by a deny prefix or access rule, by site.",
+            &self.denied.lock().unwrap(),
+        );
+        write_site_counter(
+            &mut out,
+            "traefik_pages_rate_limited_total",
+            "Requests refused by the rate limit, by site.",
+            &self.rate_limited.lock().unwrap(),
+        );
+
+        write_header(
+            &mut out,
+            "traefik_pages_rate_limit_clients",
+            "gauge",
+            "Clients being tracked by the rate limit, for each site they've used recently.",
+        );
+        let _ = writeln!(
+            out,
+            "traefik_pages_rate_limit_clients {}",
+            rate_limit_clients
+        );
+
+        write_header(
+            &mut out,
+            "traefik_pages_provider_polls_total",
+            "counter",
+            "Requests for the Traefik provider config.",
+        );
+        let _ = writeln!(
+            out,
+            "traefik_pages_provider_polls_total {}",
+            self.provider_polls.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut out,
+            "traefik_pages_discovery_duration_seconds",
+            "gauge",
+            "Time taken by the last full scan for sites.",
+        );
+        if let Some(discovery) = discovery {
+            let _ = writeln!(
+                out,
+                "traefik_pages_discovery_duration_seconds {}",
+                discovery.as_secs_f64()
+            );
+        }
+
+        write_header(
+            &mut out,
+            "traefik_pages_sites",
+            "gauge",
+            "Sites being served.",
+        );
+        let _ = writeln!(out, "traefik_pages_sites {}", sites);
+
+        write_header(
+            &mut out,
+            "traefik_pages_config_errors",
+            "gauge",
+            "Sites not being served because their config is invalid.",
+        );
+        let _ = writeln!(out, "traefik_pages_config_errors {}", config_errors);
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.001);
+        histogram.observe(0.2);
+        histogram.observe(60.0);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[5], 2);
+        assert_eq!(histogram.buckets[DURATION_BUCKETS.len() - 1], 2);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_request("localhost", StatusCode::OK, 100, Duration::from_millis(20));
+        metrics.record_request(
+            "localhost",
+            StatusCode::NOT_FOUND,
+            0,
+            Duration::from_millis(1),
+        );
+        metrics.record_denied("local\"host");
+        metrics.record_rate_limited("localhost");
+        metrics.record_provider_poll();
+
+        let rendered = metrics.render(4, 1, Some(Duration::from_millis(500)), 2);
+        for line in [
+            "traefik_pages_requests_total{site=\"localhost\",status=\"2xx\"} 1",
+            "traefik_pages_requests_total{site=\"localhost\",status=\"4xx\"} 1",
+            "traefik_pages_request_duration_seconds_bucket{site=\"localhost\",status=\"2xx\",le=\"0.01\"} 0",
+            "traefik_pages_request_duration_seconds_bucket{site=\"localhost\",status=\"2xx\",le=\"0.025\"} 1",
+            "traefik_pages_request_duration_seconds_bucket{site=\"localhost\",status=\"2xx\",le=\"+Inf\"} 1",
+            "traefik_pages_response_bytes_total{site=\"localhost\"} 100",
+            "traefik_pages_not_found_total{site=\"localhost\"} 1",
+            "traefik_pages_denied_total{site=\"local\\\"host\"} 1",
+            "traefik_pages_rate_limited_total{site=\"localhost\"} 1",
+            "traefik_pages_rate_limit_clients 2",
+            "traefik_pages_provider_polls_total 1",
+            "traefik_pages_discovery_duration_seconds 0.5",
+            "traefik_pages_sites 4",
+            "traefik_pages_config_errors 1",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "Missing {}", line);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::proxy::{get_routes_by_specificity, upstreams_use_tls, SiteRoute, Upstream};
+use serde_json::{json, Value};
+use std::io;
+
+const SERVER_NAME: &str = "traefik-pages";
+
+fn get_matcher(route: &SiteRoute) -> Value {
+    match &route.path_prefix {
+        Some(prefix) => json!({
+            "host": [&route.hostname],
+            "path": [format!("/{}", prefix), format!("/{}/*", prefix)]
+        }),
+        None => json!({ "host": [&route.hostname] }),
+    }
+}
+
+fn get_handler(upstreams: &[Upstream]) -> io::Result<Value> {
+    let dials: Vec<Value> = upstreams
+        .iter()
+        .map(|upstream| json!({ "dial": &upstream.address }))
+        .collect();
+    let mut handler = json!({
+        "handler": "reverse_proxy",
+        "upstreams": dials
+    });
+    // Caddy requires every upstream of a handler to use the same transport
+    if upstreams_use_tls(upstreams)? {
+        handler["transport"] = json!({ "protocol": "http", "tls": {} });
+    }
+    Ok(handler)
+}
+
+/// Render a config document for Caddy's admin API (`POST /load`).
+///
+/// Caddy matches routes in order, so nested sites come first.
+pub fn render(routes: &[SiteRoute], upstreams: &[Upstream]) -> io::Result<String> {
+    let handler = get_handler(upstreams)?;
+    let caddy_routes: Vec<Value> = get_routes_by_specificity(routes)
+        .into_iter()
+        .map(|route| {
+            json!({
+                "@id": &route.name,
+                "match": [get_matcher(route)],
+                "handle": [&handler],
+                "terminal": true
+            })
+        })
+        .collect();
+
+    let config = json!({
+        "apps": {
+            "http": {
+                "servers": {
+                    SERVER_NAME: {
+                        "listen": [":443"],
+                        "routes": caddy_routes
+                    }
+                }
+            }
+        }
+    });
+    serde_json::to_string_pretty(&config).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::proxy::get_site_routes;
+    use crate::test_utils::get_test_settings;
+
+    fn get_test_upstreams() -> Vec<Upstream> {
+        vec![Upstream::from_url("http://10.0.0.1:5000").unwrap()]
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let routes = get_site_routes(&get_test_settings()).await.unwrap();
+        let config: Value =
+            serde_json::from_str(&render(&routes, &get_test_upstreams()).unwrap()).unwrap();
+
+        let caddy_routes = config["apps"]["http"]["servers"][SERVER_NAME]["routes"]
+            .as_array()
+            .unwrap();
+        assert_eq!(caddy_routes.len(), routes.len());
+        assert_eq!(
+            caddy_routes[0],
+            json!({
+                "@id": "localhost__nested",
+                "match": [{
+                    "host": ["localhost"],
+                    "path": ["/nested", "/nested/*"]
+                }],
+                "handle": [{
+                    "handler": "reverse_proxy",
+                    "upstreams": [{"dial": "10.0.0.1:5000"}]
+                }],
+                "terminal": true
+            })
+        );
+        assert_eq!(caddy_routes[1]["match"], json!([{"host": ["localhost"]}]));
+    }
+
+    #[test]
+    fn test_tls_upstream() {
+        let handler =
+            get_handler(&[Upstream::from_url("https://pages.internal").unwrap()]).unwrap();
+        assert_eq!(
+            handler["upstreams"],
+            json!([{"dial": "pages.internal:443"}])
+        );
+        assert_eq!(handler["transport"], json!({"protocol": "http", "tls": {}}));
+
+        assert!(get_handler(&[
+            Upstream::from_url("https://pages.internal").unwrap(),
+            Upstream::from_url("http://10.0.0.1").unwrap(),
+        ])
+        .is_err());
+    }
+}
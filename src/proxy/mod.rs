@@ -0,0 +1,283 @@
+use crate::settings::Settings;
+use crate::site::Site;
+use std::io;
+
+pub mod caddy;
+pub mod nginx;
+pub mod traefik;
+
+/// Encode part of a route name, keeping alphanumerics and replacing `separator` with `-`.
+///
+/// Anything else is escaped as `_` followed by its hex value, so no two inputs share a name.
+fn encode_name_part(part: &str, separator: char) -> String {
+    let mut encoded = String::with_capacity(part.len());
+    for c in part.chars() {
+        if c == separator {
+            encoded.push('-');
+        } else if c.is_ascii_alphanumeric() {
+            encoded.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("_{:02x}", byte));
+            }
+        }
+    }
+    encoded
+}
+
+/// Hostnames made only of alphanumerics and dots keep the name they've always had, with `.`
+/// replaced by `-`
+fn get_route_name(hostname: &str, path_prefix: Option<&str>) -> String {
+    let hostname = encode_name_part(hostname, '.');
+    match path_prefix {
+        // Escapes are always followed by hex, so `__` can't appear in either part
+        Some(prefix) => format!("{}__{}", hostname, encode_name_part(prefix, '/')),
+        None => hostname,
+    }
+}
+
+/// Everything needed to route to a site, independent of the reverse proxy doing the routing
+#[derive(Debug, PartialEq)]
+pub struct SiteRoute {
+    pub name: String,
+    pub hostname: String,
+    pub path_prefix: Option<String>,
+    /// Set when the site overrides the globally configured Traefik service
+    pub service_override: Option<String>,
+    pub default_middlewares: bool,
+}
+
+impl SiteRoute {
+    pub fn new(site: &Site) -> Self {
+        SiteRoute {
+            name: get_route_name(&site.get_hostname(), site.get_path_prefix()),
+            hostname: site.get_hostname(),
+            path_prefix: site.get_path_prefix().map(String::from),
+            service_override: site.config.service.clone(),
+            default_middlewares: site.config.default_middlewares,
+        }
+    }
+}
+
+/// Routes for every site, ordered by name
+pub async fn get_site_routes(settings: &Settings) -> io::Result<Vec<SiteRoute>> {
+    let mut routes: Vec<SiteRoute> = settings
+        .discover_sites()
+        .await?
+        .iter()
+        .map(|site| SiteRoute::new(site))
+        .collect();
+    routes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(routes)
+}
+
+/// Routes ordered so nested sites come before the sites they're nested in, for proxies which
+/// match routes in order
+fn get_routes_by_specificity(routes: &[SiteRoute]) -> Vec<&SiteRoute> {
+    let mut ordered: Vec<&SiteRoute> = routes.iter().collect();
+    ordered.sort_by_key(|r| std::cmp::Reverse(r.path_prefix.as_deref().map(str::len)));
+    ordered
+}
+
+/// An address `traefik-pages` can be reached on by other reverse proxies
+#[derive(Debug, PartialEq)]
+pub struct Upstream {
+    /// `host:port`
+    pub address: String,
+    pub tls: bool,
+}
+
+impl Upstream {
+    pub fn from_url(url: &str) -> Option<Self> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            (false, url.strip_prefix("http://")?)
+        };
+        let host = rest.split('/').next()?;
+        if host.is_empty() {
+            return None;
+        }
+
+        // IPv6 addresses contain colons, but are bracketed
+        let has_port = host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.contains(']'));
+        let address = if has_port {
+            host.to_owned()
+        } else {
+            format!("{}:{}", host, if tls { 443 } else { 80 })
+        };
+        Some(Upstream { address, tls })
+    }
+}
+
+/// Whether `traefik-pages` is reached over TLS. Proxies use the same scheme for every upstream of
+/// a route, so they can't be mixed.
+fn upstreams_use_tls(upstreams: &[Upstream]) -> io::Result<bool> {
+    match upstreams.first() {
+        Some(first) if upstreams.iter().any(|upstream| upstream.tls != first.tls) => {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Upstreams can't use a mix of HTTP and HTTPS",
+            ))
+        }
+        Some(first) => Ok(first.tls),
+        None => Ok(false),
+    }
+}
+
+/// Reverse proxies other than Traefik which configuration can be generated for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Caddy,
+    Nginx,
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name.to_lowercase().as_str() {
+            "caddy" => Some(Backend::Caddy),
+            "nginx" => Some(Backend::Nginx),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Backend::Caddy => "application/json",
+            Backend::Nginx => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn render(&self, routes: &[SiteRoute], upstreams: &[Upstream]) -> io::Result<String> {
+        match self {
+            Backend::Caddy => caddy::render(routes, upstreams),
+            Backend::Nginx => nginx::render(routes, upstreams),
+        }
+    }
+}
+
+pub async fn get_backend_config(settings: &Settings, backend: Backend) -> io::Result<String> {
+    let upstreams: Vec<Upstream> = settings
+        .upstream_urls
+        .iter()
+        .filter_map(|url| Upstream::from_url(url))
+        .collect();
+    backend.render(&get_site_routes(settings).await?, &upstreams)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::{get_test_settings, TestDir};
+
+    #[test]
+    fn test_route_name() {
+        assert_eq!(get_route_name("localhost", None), "localhost");
+        assert_eq!(get_route_name("site1.localhost", None), "site1-localhost");
+        assert_eq!(
+            get_route_name("no-index.localhost", None),
+            "no_2dindex-localhost"
+        );
+        assert_eq!(
+            get_route_name("localhost", Some("docs/v1")),
+            "localhost__docs-v1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_names_unique() {
+        let root = TestDir::new("unique-route-names");
+        // `a_2db.example.com` would have been named the same as `a-b.example.com` when escaped
+        for hostname in ["a-b.example.com", "a.b-example.com", "a_2db.example.com"] {
+            root.write(&format!("{}/index.html", hostname), hostname);
+        }
+        root.write("a.b/c/pages.toml", "site = true");
+        root.write("a.b.c/index.html", "");
+        let names: Vec<String> = get_site_routes(&root.settings())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "a-b",
+                "a-b-c",
+                "a-b_2dexample-com",
+                "a-b__c",
+                "a_2db-example-com",
+                "a_5f2db-example-com",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_name_part() {
+        assert_eq!(
+            encode_name_part("a-b.example.com", '.'),
+            "a_2db-example-com"
+        );
+        assert_eq!(
+            encode_name_part("a.b-example.com", '.'),
+            "a-b_2dexample-com"
+        );
+        assert_eq!(encode_name_part("docs/v1.2", '/'), "docs-v1_2e2");
+        assert_eq!(encode_name_part("bücher.de", '.'), "b_c3_bccher-de");
+    }
+
+    #[tokio::test]
+    async fn test_site_routes() {
+        let routes = get_site_routes(&get_test_settings()).await.unwrap();
+        assert_eq!(routes.len(), 5);
+        assert_eq!(
+            routes[0],
+            SiteRoute {
+                name: String::from("localhost"),
+                hostname: String::from("localhost"),
+                path_prefix: None,
+                service_override: None,
+                default_middlewares: true,
+            }
+        );
+        assert_eq!(routes[1].path_prefix.as_deref(), Some("nested"));
+
+        let ordered = get_routes_by_specificity(&routes);
+        assert_eq!(ordered[0].name, "localhost__nested");
+    }
+
+    #[test]
+    fn test_upstream_from_url() {
+        assert_eq!(
+            Upstream::from_url("http://10.0.0.1:5000"),
+            Some(Upstream {
+                address: String::from("10.0.0.1:5000"),
+                tls: false
+            })
+        );
+        assert_eq!(
+            Upstream::from_url("https://pages.internal/"),
+            Some(Upstream {
+                address: String::from("pages.internal:443"),
+                tls: true
+            })
+        );
+        assert_eq!(
+            Upstream::from_url("http://[::1]").unwrap().address,
+            "[::1]:80"
+        );
+        assert_eq!(Upstream::from_url("10.0.0.1:5000"), None);
+        assert_eq!(Upstream::from_url("http://"), None);
+    }
+
+    #[test]
+    fn test_backend_from_name() {
+        assert_eq!(Backend::from_name("Caddy"), Some(Backend::Caddy));
+        assert_eq!(Backend::from_name("nginx"), Some(Backend::Nginx));
+        assert_eq!(Backend::from_name("haproxy"), None);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::proxy::{get_routes_by_specificity, upstreams_use_tls, SiteRoute, Upstream};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+
+const UPSTREAM_NAME: &str = "traefik_pages";
+/// Set by the `map`, from the request's hostname
+const UPSTREAM_VARIABLE: &str = "$traefik_pages_upstream";
+
+/// Quote a value so nginx treats it as a single literal string
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn write_location(config: &mut String, modifier: &str, path: &str) {
+    writeln!(config, "    location {}{} {{", modifier, quote(path)).unwrap();
+    writeln!(config, "        proxy_pass {};", UPSTREAM_VARIABLE).unwrap();
+    writeln!(config, "        proxy_set_header Host $host;").unwrap();
+    writeln!(
+        config,
+        "        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;"
+    )
+    .unwrap();
+    writeln!(
+        config,
+        "        proxy_set_header X-Forwarded-Proto $scheme;"
+    )
+    .unwrap();
+    writeln!(config, "    }}").unwrap();
+}
+
+/// Render an include file with an `upstream` for `traefik-pages`, a `map` from each hostname to
+/// it, and a `server` for each hostname with a `location` for each of its sites.
+pub fn render(routes: &[SiteRoute], upstreams: &[Upstream]) -> io::Result<String> {
+    let scheme = if upstreams_use_tls(upstreams)? {
+        "https"
+    } else {
+        "http"
+    };
+
+    let mut config = String::from("# Generated by traefik-pages\n\n");
+
+    writeln!(config, "upstream {} {{", UPSTREAM_NAME).unwrap();
+    for upstream in upstreams {
+        writeln!(config, "    server {};", upstream.address).unwrap();
+    }
+    writeln!(config, "}}").unwrap();
+
+    let mut hostnames: BTreeMap<&str, Vec<&SiteRoute>> = BTreeMap::new();
+    for route in get_routes_by_specificity(routes) {
+        hostnames.entry(&route.hostname).or_default().push(route);
+    }
+
+    writeln!(config, "\nmap $host {} {{", UPSTREAM_VARIABLE).unwrap();
+    writeln!(config, "    hostnames;").unwrap();
+    writeln!(config, "    default \"\";").unwrap();
+    for hostname in hostnames.keys() {
+        writeln!(
+            config,
+            "    {} {};",
+            quote(hostname),
+            quote(&format!("{}://{}", scheme, UPSTREAM_NAME))
+        )
+        .unwrap();
+    }
+    writeln!(config, "}}").unwrap();
+
+    for (hostname, hostname_routes) in hostnames {
+        writeln!(config, "\nserver {{").unwrap();
+        writeln!(config, "    listen 80;").unwrap();
+        writeln!(config, "    server_name {};", quote(hostname)).unwrap();
+        for route in hostname_routes {
+            writeln!(config, "\n    # {}", route.name).unwrap();
+            match &route.path_prefix {
+                Some(prefix) => {
+                    write_location(&mut config, "= ", &format!("/{}", prefix));
+                    write_location(&mut config, "", &format!("/{}/", prefix));
+                }
+                None => write_location(&mut config, "", "/"),
+            }
+        }
+        writeln!(config, "}}").unwrap();
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::proxy::get_site_routes;
+    use crate::test_utils::get_test_settings;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/docs"), "\"/docs\"");
+        assert_eq!(quote("/a \"b\"\\"), "\"/a \\\"b\\\"\\\\\"");
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let routes = get_site_routes(&get_test_settings()).await.unwrap();
+        let config = render(
+            &routes,
+            &[
+                Upstream::from_url("http://10.0.0.1:5000").unwrap(),
+                Upstream::from_url("http://10.0.0.2:5000").unwrap(),
+            ],
+        )
+        .unwrap();
+
+        assert!(config.contains(
+            "upstream traefik_pages {\n    server 10.0.0.1:5000;\n    server 10.0.0.2:5000;\n}\n"
+        ));
+        assert_eq!(config.matches("server {").count(), 4);
+        assert!(config.contains("server_name \"site1.localhost\";"));
+        assert!(config.contains("proxy_pass $traefik_pages_upstream;"));
+        assert!(config.contains(
+            "map $host $traefik_pages_upstream {\n    hostnames;\n    default \"\";\n    \"localhost\" \"http://traefik_pages\";\n"
+        ));
+        assert!(config.contains("    \"site1.localhost\" \"http://traefik_pages\";\n"));
+
+        // Nested sites are listed before their parent within the same server
+        let nested = config.find("location = \"/nested\" {").unwrap();
+        let nested_dir = config.find("location \"/nested/\" {").unwrap();
+        let root = config.find("location \"/\" {").unwrap();
+        assert!(nested < nested_dir && nested_dir < root);
+    }
+
+    #[tokio::test]
+    async fn test_render_schemes() {
+        let routes = get_site_routes(&get_test_settings()).await.unwrap();
+        let https = Upstream::from_url("https://10.0.0.1").unwrap();
+        let http = Upstream::from_url("http://10.0.0.2").unwrap();
+
+        let config = render(&routes, &[https]).unwrap();
+        assert!(config.contains("\"localhost\" \"https://traefik_pages\";"));
+        assert!(!config.contains("http://"));
+
+        let https = Upstream::from_url("https://10.0.0.1").unwrap();
+        assert_eq!(
+            render(&routes, &[https, http]).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
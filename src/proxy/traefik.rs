@@ -0,0 +1,347 @@
+use crate::proxy::{get_route_name, get_site_routes, SiteRoute};
+use crate::routes::INTERNAL_ROUTE_PREFIX;
+use crate::settings::Settings;
+use crate::validation::{validate_provider_config, Diagnostic};
+use log::warn;
+use serde_json::{json, Map, Value};
+use std::io;
+
+const DEFAULT_MIDDLEWARE_NAME: &str = "tp-default";
+const RETRY_MIDDLEWARE_NAME: &str = "tp-retry";
+const NESTED_SITE_PRIORITY: usize = 1000;
+
+pub fn get_router_rule(route: &SiteRoute) -> String {
+    let host_rule = format!("Host(`{}`)", route.hostname);
+    match &route.path_prefix {
+        Some(prefix) => format!(
+            "{} && (Path(`/{prefix}`) || PathPrefix(`/{prefix}/`))",
+            host_rule,
+            prefix = prefix
+        ),
+        None => host_rule,
+    }
+}
+
+/// Traefik defaults to the rule length, but nested sites must always win over their parent
+pub fn get_router_priority(route: &SiteRoute, rule: &str) -> Option<usize> {
+    route
+        .path_prefix
+        .as_ref()
+        .map(|_| rule.len() + NESTED_SITE_PRIORITY)
+}
+
+pub fn get_router_middlewares(route: &SiteRoute) -> Vec<String> {
+    if route.default_middlewares {
+        vec![String::from(DEFAULT_MIDDLEWARE_NAME)]
+    } else {
+        vec![]
+    }
+}
+
+/// Routers look funny, so no point defining as a struct
+fn serialize_router(route: &SiteRoute, settings: &Settings) -> Value {
+    let rule = get_router_rule(route);
+    let mut router = Map::new();
+    if let Some(priority) = get_router_priority(route, &rule) {
+        router.insert(String::from("priority"), json!(priority));
+    }
+    router.insert(String::from("rule"), json!(rule));
+    router.insert(
+        String::from("service"),
+        json!(route
+            .service_override
+            .as_ref()
+            .unwrap_or(&settings.traefik_service)),
+    );
+    let middlewares = get_router_middlewares(route);
+    if !middlewares.is_empty() {
+        router.insert(String::from("middlewares"), json!(middlewares));
+    }
+    if let Some(cert_resolver) = &settings.traefik_cert_resolver {
+        router.insert(
+            String::from("tls"),
+            json!({ "certResolver": cert_resolver }),
+        );
+    }
+    Value::Object(router)
+}
+
+pub fn get_middleware(settings: &Settings) -> Value {
+    let mut retry = json!({ "attempts": settings.retry_attempts });
+    if let Some(initial_interval) = &settings.retry_initial_interval {
+        retry
+            .as_object_mut()
+            .unwrap()
+            .insert(String::from("initialInterval"), json!(initial_interval));
+    }
+
+    let chain: Vec<&str> = std::iter::once(RETRY_MIDDLEWARE_NAME)
+        .chain(settings.default_middlewares.iter().map(String::as_str))
+        .collect();
+
+    json!({
+        DEFAULT_MIDDLEWARE_NAME: {
+            "chain": {
+                "middlewares": chain
+            }
+        },
+        RETRY_MIDDLEWARE_NAME: {
+            "retry": retry
+        }
+    })
+}
+
+/// The service for `traefik-pages` itself, if it should be defined by the provider
+fn get_services(settings: &Settings) -> Option<Value> {
+    if settings.traefik_service_urls.is_empty() {
+        return None;
+    }
+
+    let servers: Vec<Value> = settings
+        .traefik_service_urls
+        .iter()
+        .map(|url| json!({ "url": url }))
+        .collect();
+
+    Some(json!({
+        &settings.traefik_service: {
+            "loadBalancer": {
+                "servers": servers,
+                "passHostHeader": true,
+                "healthCheck": {
+                    "path": format!("/{}/health", INTERNAL_ROUTE_PREFIX)
+                }
+            }
+        }
+    }))
+}
+
+/// Build the dynamic configuration document Traefik's providers consume, along with any
+/// problems found with it.
+pub async fn build_provider_config(settings: &Settings) -> io::Result<(Value, Vec<Diagnostic>)> {
+    let mut diagnostics = vec![];
+    let mut routers = Map::new();
+
+    for route in get_site_routes(settings).await? {
+        if routers.contains_key(&route.name) {
+            diagnostics.push(Diagnostic::new(&route.name, "Duplicate router name"));
+            continue;
+        }
+        routers.insert(route.name.clone(), serialize_router(&route, settings));
+    }
+
+    let mut http = json!({
+        "routers": routers,
+        "middlewares": get_middleware(settings)
+    });
+    if let Some(services) = get_services(settings) {
+        http.as_object_mut()
+            .unwrap()
+            .insert(String::from("services"), services);
+    }
+
+    let mut config = json!({ "http": http });
+    diagnostics.extend(validate_provider_config(&mut config));
+
+    for site in settings.discover_sites().await? {
+        for error in site.nested_config_errors.iter() {
+            diagnostics.push(Diagnostic::new(
+                &get_route_name(&site.get_hostname(), Some(&error.path_prefix)),
+                format!(
+                    "Invalid config, so served as part of the parent site: {}",
+                    error.error
+                ),
+            ));
+        }
+    }
+    Ok((config, diagnostics))
+}
+
+fn log_diagnostics(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics.iter() {
+        warn!(
+            "Problem with provider config for {}: {}",
+            diagnostic.name, diagnostic.problem
+        );
+    }
+}
+
+/// The configuration Traefik's providers read, which mustn't include the diagnostics, as Traefik
+/// rejects fields it doesn't know about
+pub async fn get_provider_config(settings: &Settings) -> io::Result<Value> {
+    let (config, diagnostics) = build_provider_config(settings).await?;
+    log_diagnostics(&diagnostics);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::{get_test_settings, TestDir};
+
+    async fn get_test_route(hostname: &str, url_path: &str) -> SiteRoute {
+        let settings = get_test_settings();
+        SiteRoute::new(
+            &settings
+                .site_from_hostname(hostname, url_path)
+                .await
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_default_middleware() {
+        let middleware = get_middleware(&get_test_settings());
+        let default_middleware = &middleware[DEFAULT_MIDDLEWARE_NAME];
+        let chain_middlewares = default_middleware["chain"]["middlewares"]
+            .as_array()
+            .unwrap();
+        assert_eq!(chain_middlewares.len(), 1);
+        for m in chain_middlewares.iter() {
+            assert!(middleware.get(m.as_str().unwrap()).is_some());
+        }
+        assert_eq!(
+            middleware[RETRY_MIDDLEWARE_NAME],
+            json!({"retry": {"attempts": 4}})
+        );
+    }
+
+    #[test]
+    fn test_custom_middleware() {
+        let mut settings = get_test_settings();
+        settings.retry_attempts = 2;
+        settings.retry_initial_interval = Some(String::from("100ms"));
+        settings.default_middlewares = vec![String::from("compress"), String::from("headers@file")];
+
+        let middleware = get_middleware(&settings);
+        assert_eq!(
+            middleware[DEFAULT_MIDDLEWARE_NAME]["chain"]["middlewares"],
+            json!([RETRY_MIDDLEWARE_NAME, "compress", "headers@file"])
+        );
+        assert_eq!(
+            middleware[RETRY_MIDDLEWARE_NAME],
+            json!({"retry": {"attempts": 2, "initialInterval": "100ms"}})
+        );
+    }
+
+    #[test]
+    fn test_no_services() {
+        assert!(get_services(&get_test_settings()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_services() {
+        let mut settings = get_test_settings();
+        settings.traefik_service = String::from("traefik-pages");
+        settings.traefik_service_urls = vec![
+            String::from("http://10.0.0.1:5000"),
+            String::from("http://10.0.0.2:5000"),
+        ];
+
+        assert_eq!(
+            get_services(&settings).unwrap(),
+            json!({
+                "traefik-pages": {
+                    "loadBalancer": {
+                        "servers": [
+                            {"url": "http://10.0.0.1:5000"},
+                            {"url": "http://10.0.0.2:5000"}
+                        ],
+                        "passHostHeader": true,
+                        "healthCheck": {
+                            "path": "/.traefik-pages/health"
+                        }
+                    }
+                }
+            })
+        );
+
+        let config = get_provider_config(&settings).await.unwrap();
+        assert_eq!(config["http"]["services"], get_services(&settings).unwrap());
+        assert_eq!(
+            config["http"]["routers"]["localhost"]["service"],
+            "traefik-pages"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_diagnostics() {
+        let (_, diagnostics) = build_provider_config(&get_test_settings()).await.unwrap();
+        assert!(diagnostics.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics() {
+        let mut settings = get_test_settings();
+        settings.default_middlewares = vec![String::from("compress")];
+        let (config, diagnostics) = build_provider_config(&settings).await.unwrap();
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::new(
+                DEFAULT_MIDDLEWARE_NAME,
+                "Unknown middleware compress"
+            )]
+        );
+        assert!(config["http"]["routers"].get("localhost").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_nested_config_diagnostics() {
+        let root = TestDir::new("nested-config-diagnostics");
+        root.write("example.com/index.html", "");
+        root.write("example.com/docs/pages.toml", "site = ");
+        let settings = root.settings();
+        let (config, diagnostics) = build_provider_config(&settings).await.unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].name, "example-com__docs");
+        assert!(diagnostics[0]
+            .problem
+            .starts_with("Invalid config, so served as part of the parent site"));
+        assert_eq!(config["http"]["routers"].as_object().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_serialize_router() {
+        let settings = get_test_settings();
+        assert_eq!(
+            serialize_router(&get_test_route("localhost", "").await, &settings),
+            json!({
+                "middlewares": [DEFAULT_MIDDLEWARE_NAME],
+                "rule": "Host(`localhost`)",
+                "service": "traefik-service@docker",
+                "tls": {
+                    "certResolver": "le"
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serialize_nested_router() {
+        let mut settings = get_test_settings();
+        settings.traefik_cert_resolver = None;
+        let nested_route = get_test_route("localhost", "nested/").await;
+        assert_eq!(nested_route.name, "localhost__nested");
+
+        let rule = "Host(`localhost`) && (Path(`/nested`) || PathPrefix(`/nested/`))";
+        assert_eq!(
+            serialize_router(&nested_route, &settings),
+            json!({
+                "middlewares": [DEFAULT_MIDDLEWARE_NAME],
+                "priority": rule.len() + NESTED_SITE_PRIORITY,
+                "rule": rule,
+                "service": "traefik-service@docker",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serialize_router_without_default_middlewares() {
+        let settings = get_test_settings();
+        let route = get_test_route("no-middleware.localhost", "").await;
+        assert!(serialize_router(&route, &settings)
+            .get("middlewares")
+            .is_none());
+    }
+}
@@ -0,0 +1,169 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often buckets which have refilled are forgotten, so the map doesn't grow forever
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// Requests per second, on average
+    pub rate: f64,
+    /// Requests which can be made at once, before being limited to `rate`
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.rate > 0.0 && self.rate.is_finite()) {
+            return Err(String::from("Rate limit rate must be above 0"));
+        }
+        if self.burst == 0 {
+            return Err(String::from("Rate limit burst must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket will have refilled, at which point it's no different to a new one
+    full_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - self.tokens) / limit.rate));
+        }
+        self.tokens -= 1.0;
+        self.full_at =
+            now + Duration::from_secs_f64((limit.burst as f64 - self.tokens) / limit.rate);
+        Ok(())
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<(IpAddr, String), Bucket>,
+    swept: Instant,
+}
+
+/// Token buckets for each client of each site
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter {
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Take a request from `ip`'s bucket for `site`, or how long until it can try again
+    pub fn check(&self, ip: IpAddr, site: &str, limit: &RateLimit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if now.duration_since(buckets.swept) >= SWEEP_INTERVAL {
+            buckets.buckets.retain(|_, bucket| bucket.full_at > now);
+            buckets.swept = now;
+        }
+
+        buckets
+            .buckets
+            .entry((ip, site.to_owned()))
+            .or_insert_with(|| Bucket {
+                tokens: limit.burst as f64,
+                updated: now,
+                full_at: now,
+            })
+            .take(limit, now)
+    }
+
+    pub fn tracked(&self) -> usize {
+        self.buckets.lock().unwrap().buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread::sleep;
+
+    #[test]
+    fn test_validate() {
+        assert!(RateLimit {
+            rate: 0.5,
+            burst: 1
+        }
+        .validate()
+        .is_ok());
+        assert!(RateLimit {
+            rate: 0.0,
+            burst: 1
+        }
+        .validate()
+        .is_err());
+        assert!(RateLimit {
+            rate: 1.0,
+            burst: 0
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_limits_after_burst() {
+        let limiter = RateLimiter::default();
+        let limit = RateLimit {
+            rate: 1.0,
+            burst: 3,
+        };
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(limiter.check(ip, "localhost", &limit).is_ok());
+        }
+        let retry_after = limiter.check(ip, "localhost", &limit).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(1));
+
+        // Other clients and other sites have their own buckets
+        assert!(limiter
+            .check("10.0.0.2".parse().unwrap(), "localhost", &limit)
+            .is_ok());
+        assert!(limiter.check(ip, "example.com", &limit).is_ok());
+        assert_eq!(limiter.tracked(), 3);
+    }
+
+    #[test]
+    fn test_refills() {
+        let limiter = RateLimiter::default();
+        let limit = RateLimit {
+            rate: 50.0,
+            burst: 1,
+        };
+        let ip: IpAddr = "::1".parse().unwrap();
+        assert!(limiter.check(ip, "localhost", &limit).is_ok());
+        assert!(limiter.check(ip, "localhost", &limit).is_err());
+
+        sleep(Duration::from_millis(30));
+        assert!(limiter.check(ip, "localhost", &limit).is_ok());
+    }
+}
@@ -0,0 +1,535 @@
+use crate::files::is_dir;
+use crate::releases::CURRENT_LINK;
+use crate::site::{is_valid_hostname, Site};
+use crate::site_config::CONFIG_FILENAME;
+use log::{error, info, warn};
+use notify::{recommended_watcher, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::time::delay_for;
+
+/// How long to wait for filesystem events to settle before refreshing sites
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Sites for a hostname, ordered from the longest path prefix to the site at the root
+type HostnameSites = Vec<Arc<Site>>;
+
+fn group_by_hostname(sites: Vec<Site>) -> HashMap<String, HostnameSites> {
+    let mut grouped: HashMap<String, HostnameSites> = HashMap::new();
+    for site in sites {
+        grouped
+            .entry(site.get_hostname())
+            .or_default()
+            .push(Arc::new(site));
+    }
+    for hostname_sites in grouped.values_mut() {
+        hostname_sites.sort_by_key(|s| std::cmp::Reverse(s.get_path_prefix().map(str::len)));
+    }
+    grouped
+}
+
+/// Directories whose entries can change a hostname's sites: the site's own directory (holding
+/// `current`), and the directories its sites are served from (holding their `pages.toml`)
+fn get_watch_paths(site_dir: &Path, hostname_sites: Option<&HostnameSites>) -> HashSet<PathBuf> {
+    let mut paths = HashSet::new();
+    let hostname_sites = match hostname_sites {
+        Some(s) => s,
+        None => return paths,
+    };
+    paths.insert(site_dir.to_path_buf());
+    for site in hostname_sites {
+        paths.insert(site.get_root().to_path_buf());
+        for nested_error in site.nested_config_errors.iter() {
+            if let Some(dir) = nested_error.config_path.parent() {
+                paths.insert(dir.to_path_buf());
+            }
+        }
+    }
+    paths
+}
+
+fn log_watch_error(path: &Path, e: &notify::Error) {
+    match e.kind {
+        notify::ErrorKind::MaxFilesWatch => error!(
+            "Failed to watch {} for changes, as the limit on watches has been reached (on Linux, raise fs.inotify.max_user_watches). Relying on periodic rescans.",
+            path.display()
+        ),
+        _ => warn!(
+            "Failed to watch {} for changes, relying on periodic rescans: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Only the directories which matter are watched, rather than everything under the sites root,
+/// so large sites and old releases don't use up the system's watches
+struct Watches {
+    watcher: RecommendedWatcher,
+    /// Paths watched for each hostname
+    paths: HashMap<String, HashSet<PathBuf>>,
+}
+
+/// In-memory view of the sites on disk, shared between workers
+pub struct SiteRegistry {
+    sites_root: PathBuf,
+    sites: RwLock<HashMap<String, HostnameSites>>,
+    loaded: AtomicBool,
+    generation: AtomicU64,
+    generation_tx: watch::Sender<u64>,
+    generation_rx: watch::Receiver<u64>,
+    last_discovery: RwLock<Option<Duration>>,
+    /// Why the last full scan failed, if it did
+    last_rescan_error: RwLock<Option<io::ErrorKind>>,
+    /// Only set once `watch` is called
+    watches: Mutex<Option<Watches>>,
+}
+
+impl SiteRegistry {
+    pub fn new(sites_root: PathBuf) -> Self {
+        let (generation_tx, generation_rx) = watch::channel(0);
+        SiteRegistry {
+            sites_root,
+            sites: RwLock::new(HashMap::new()),
+            loaded: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            generation_tx,
+            generation_rx,
+            last_discovery: RwLock::new(None),
+            last_rescan_error: RwLock::new(None),
+            watches: Mutex::new(None),
+        }
+    }
+
+    pub fn sites_root(&self) -> &Path {
+        &self.sites_root
+    }
+
+    /// Incremented whenever the set of sites, or any site's config, changes
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Receive the new generation whenever the registry changes
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.generation_rx.clone()
+    }
+
+    fn bump_generation(&self) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        // There's always at least one receiver, held by the registry itself
+        let _ = self.generation_tx.broadcast(generation);
+    }
+
+    async fn ensure_loaded(&self) -> io::Result<()> {
+        if !self.loaded.load(Ordering::SeqCst) {
+            self.rescan().await?;
+        }
+        Ok(())
+    }
+
+    /// Replace the registry contents with what's currently on disk
+    pub async fn rescan(&self) -> io::Result<()> {
+        let started = Instant::now();
+        let discovered = Site::discover_all(&self.sites_root).await;
+        *self.last_rescan_error.write().unwrap() = discovered.as_ref().err().map(io::Error::kind);
+        let sites = group_by_hostname(discovered?);
+        *self.last_discovery.write().unwrap() = Some(started.elapsed());
+
+        let changed = {
+            let mut current = self.sites.write().unwrap();
+            let changed = *current != sites;
+            if changed {
+                *current = sites;
+            }
+            changed
+        };
+        self.loaded.store(true, Ordering::SeqCst);
+
+        if changed {
+            self.bump_generation();
+        }
+        self.update_all_watches();
+        Ok(())
+    }
+
+    /// Re-read a single hostname's sites from disk, adding or removing them as necessary
+    pub async fn refresh_site(&self, hostname: &str) {
+        let site_root = self.sites_root.join(hostname);
+        let discovered = if is_valid_hostname(hostname) && is_dir(&site_root).await {
+            match Site::discover_for_hostname(&self.sites_root, hostname).await {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("Failed to discover sites for {}: {}", hostname, e);
+                    return;
+                }
+            }
+        } else {
+            vec![]
+        };
+
+        let changed = {
+            let mut sites = self.sites.write().unwrap();
+            match group_by_hostname(discovered).remove(hostname) {
+                Some(hostname_sites) => {
+                    sites.insert(hostname.to_owned(), hostname_sites.clone())
+                        != Some(hostname_sites)
+                }
+                None => sites.remove(hostname).is_some(),
+            }
+        };
+
+        if changed {
+            self.bump_generation();
+        }
+        // The directories may have been replaced, even if their paths are the same
+        self.update_watches(hostname, true);
+    }
+
+    /// Watch the directories for a hostname's sites, and stop watching any which no longer matter.
+    ///
+    /// With `rewatch`, directories which were already watched are watched again.
+    fn update_watches(&self, hostname: &str, rewatch: bool) {
+        let mut watches = self.watches.lock().unwrap();
+        let watches = match watches.as_mut() {
+            Some(w) => w,
+            None => return,
+        };
+        let wanted = get_watch_paths(
+            &self.sites_root.join(hostname),
+            self.sites.read().unwrap().get(hostname),
+        );
+        let watched = watches.paths.remove(hostname).unwrap_or_default();
+
+        for path in watched.iter() {
+            if rewatch || !wanted.contains(path) {
+                // Fails if the directory is already gone, which removes its watch anyway
+                let _ = watches.watcher.unwatch(path);
+            }
+        }
+        let mut now_watched = HashSet::new();
+        for path in wanted {
+            if !rewatch && watched.contains(&path) {
+                now_watched.insert(path);
+                continue;
+            }
+            // Failed watches aren't recorded, so they're tried again on the next rescan
+            match watches.watcher.watch(&path, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    now_watched.insert(path);
+                }
+                Err(e) => log_watch_error(&path, &e),
+            }
+        }
+        if !now_watched.is_empty() {
+            watches.paths.insert(hostname.to_owned(), now_watched);
+        }
+    }
+
+    /// Bring the watches in line with every hostname, as sites may have changed without an event
+    fn update_all_watches(&self) {
+        let mut hostnames: HashSet<String> = match self.watches.lock().unwrap().as_ref() {
+            Some(watches) => watches.paths.keys().cloned().collect(),
+            None => return,
+        };
+        hostnames.extend(self.sites.read().unwrap().keys().cloned());
+        for hostname in hostnames {
+            self.update_watches(&hostname, false);
+        }
+    }
+
+    /// How long the last full scan of the sites root took
+    pub fn last_discovery_duration(&self) -> Option<Duration> {
+        *self.last_discovery.read().unwrap()
+    }
+
+    /// Whether a full scan has succeeded yet
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.load(Ordering::SeqCst)
+    }
+
+    pub fn last_rescan_error(&self) -> Option<io::ErrorKind> {
+        *self.last_rescan_error.read().unwrap()
+    }
+
+    /// How many sites are valid and invalid, as of the last scan, without going to disk
+    pub fn site_counts(&self) -> (usize, usize) {
+        let sites = self.sites.read().unwrap();
+        let invalid = sites.values().flatten().filter(|s| !s.is_valid()).count();
+        (
+            sites.values().map(Vec::len).sum::<usize>() - invalid,
+            invalid,
+        )
+    }
+
+    /// How many sites aren't served because their config is invalid
+    pub async fn config_error_count(&self) -> io::Result<usize> {
+        self.ensure_loaded().await?;
+        Ok(self
+            .sites
+            .read()
+            .unwrap()
+            .values()
+            .flatten()
+            .filter(|s| !s.is_valid())
+            .count())
+    }
+
+    /// All sites which can be served
+    pub async fn sites(&self) -> io::Result<Vec<Arc<Site>>> {
+        self.ensure_loaded().await?;
+        Ok(self
+            .sites
+            .read()
+            .unwrap()
+            .values()
+            .flatten()
+            .filter(|s| s.is_valid())
+            .cloned()
+            .collect())
+    }
+
+    /// Find the site serving a URL path, preferring the longest matching path prefix
+    pub async fn get(&self, hostname: &str, url_path: &str) -> Option<Arc<Site>> {
+        self.ensure_loaded().await.ok()?;
+        self.sites
+            .read()
+            .unwrap()
+            .get(hostname)?
+            .iter()
+            .find(|s| s.strip_path_prefix(url_path).is_some())
+            .filter(|s| s.is_valid())
+            .cloned()
+    }
+
+    /// Determine which site a filesystem event affects, if it could change the registry.
+    ///
+    /// Only sites being created or removed, releases being activated, and changes to their
+    /// config, are relevant.
+    fn hostname_for_event_path(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.sites_root).ok()?;
+        let mut components = relative.components();
+        let hostname = match components.next()? {
+            Component::Normal(c) => c.to_str()?.to_owned(),
+            _ => return None,
+        };
+        let is_release_pointer = relative == Path::new(&hostname).join(CURRENT_LINK);
+        if components.next().is_none() || is_release_pointer || path.file_name()? == CONFIG_FILENAME
+        {
+            return Some(hostname);
+        }
+        None
+    }
+
+    pub async fn rescan_periodically(self: Arc<Self>, interval: Duration) {
+        loop {
+            delay_for(interval).await;
+            if let Err(e) = self.rescan().await {
+                error!("Failed to rescan sites: {}", e);
+            }
+        }
+    }
+
+    /// Watch the sites root for changes, refreshing affected sites as they happen.
+    ///
+    /// Besides the sites root itself, only each site's directory and the directories its sites
+    /// are served from are watched, so new nested sites are found by the periodic rescan.
+    pub fn watch(self: Arc<Self>) -> notify::Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let registry = self.clone();
+
+        let mut watcher = recommended_watcher(move |res: notify::Result<Event>| match res {
+            Ok(event) => {
+                for hostname in event
+                    .paths
+                    .iter()
+                    .filter_map(|p| registry.hostname_for_event_path(p))
+                {
+                    let _ = tx.send(hostname);
+                }
+            }
+            Err(e) => warn!("Filesystem watch error: {}", e),
+        })?;
+        watcher.watch(&self.sites_root, RecursiveMode::NonRecursive)?;
+        *self.watches.lock().unwrap() = Some(Watches {
+            watcher,
+            paths: HashMap::new(),
+        });
+        self.update_all_watches();
+
+        info!("Watching {} for changes", self.sites_root.display());
+
+        tokio::spawn(async move {
+            while let Some(hostname) = rx.recv().await {
+                let mut hostnames = HashSet::new();
+                hostnames.insert(hostname);
+
+                delay_for(WATCH_DEBOUNCE).await;
+                while let Ok(hostname) = rx.try_recv() {
+                    hostnames.insert(hostname);
+                }
+
+                for hostname in hostnames {
+                    self.refresh_site(&hostname).await;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::releases::{self, RELEASES_DIR};
+    use crate::test_utils::{get_example_dir, TestDir};
+
+    #[tokio::test]
+    async fn test_lazy_load() {
+        let registry = SiteRegistry::new(get_example_dir());
+        assert_eq!(registry.generation(), 0);
+        assert_eq!(registry.sites().await.unwrap().len(), 5);
+        assert_eq!(registry.generation(), 1);
+        assert_eq!(*registry.subscribe().borrow(), 1);
+        assert!(registry.get("localhost", "").await.is_some());
+        assert!(registry.get("unknown", "").await.is_none());
+        assert!(registry.last_discovery_duration().is_some());
+        assert_eq!(registry.config_error_count().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_longest_prefix() {
+        let registry = SiteRegistry::new(get_example_dir());
+        for (path, prefix) in [
+            ("", None),
+            ("sub/index.html", None),
+            ("nested-sibling", None),
+            ("nested", Some("nested")),
+            ("nested/index.html", Some("nested")),
+        ] {
+            assert_eq!(
+                registry
+                    .get("localhost", path)
+                    .await
+                    .unwrap()
+                    .get_path_prefix(),
+                prefix
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rescan_unchanged() {
+        let registry = SiteRegistry::new(get_example_dir());
+        registry.rescan().await.unwrap();
+        registry.rescan().await.unwrap();
+        registry.refresh_site("localhost").await;
+        assert_eq!(registry.generation(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_site() {
+        let root = TestDir::new("registry");
+        let registry = SiteRegistry::new(root.to_path_buf());
+        assert!(registry.sites().await.unwrap().is_empty());
+
+        std::fs::create_dir(root.join("example.com")).unwrap();
+        registry.refresh_site("example.com").await;
+        assert!(registry.get("example.com", "").await.is_some());
+
+        std::fs::write(
+            root.join("example.com").join(CONFIG_FILENAME),
+            "dir_index = 1",
+        )
+        .unwrap();
+        registry.refresh_site("example.com").await;
+        assert!(registry.get("example.com", "").await.is_none());
+
+        std::fs::remove_dir_all(root.join("example.com")).unwrap();
+        registry.refresh_site("example.com").await;
+        assert!(registry.sites().await.unwrap().is_empty());
+        assert_eq!(registry.generation(), 3);
+    }
+
+    #[test]
+    fn test_hostname_for_event_path() {
+        let registry = SiteRegistry::new(get_example_dir());
+        assert_eq!(
+            registry.hostname_for_event_path(&get_example_dir().join("localhost")),
+            Some(String::from("localhost"))
+        );
+        assert_eq!(
+            registry.hostname_for_event_path(
+                &get_example_dir().join("localhost").join(CONFIG_FILENAME)
+            ),
+            Some(String::from("localhost"))
+        );
+        assert_eq!(
+            registry.hostname_for_event_path(&get_example_dir().join("localhost/index.html")),
+            None
+        );
+        assert_eq!(
+            registry.hostname_for_event_path(&get_example_dir().join("localhost/current")),
+            Some(String::from("localhost"))
+        );
+        assert_eq!(
+            registry.hostname_for_event_path(&PathBuf::from("/somewhere/else")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_paths() {
+        let registry = SiteRegistry::new(get_example_dir());
+        registry.rescan().await.unwrap();
+        let site_dir = get_example_dir().join("localhost");
+        let paths = get_watch_paths(&site_dir, registry.sites.read().unwrap().get("localhost"));
+        assert_eq!(
+            paths,
+            vec![site_dir.clone(), site_dir.join("nested")]
+                .into_iter()
+                .collect()
+        );
+        assert!(get_watch_paths(&site_dir, None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watches_follow_releases() {
+        let root = releases::tests::create_releases("registry-watches", &["1", "2"]);
+        let site_dir = root.join("example.com");
+        let registry = Arc::new(SiteRegistry::new(root.to_path_buf()));
+        registry.rescan().await.unwrap();
+        registry.clone().watch().unwrap();
+
+        let watched = |registry: &SiteRegistry| {
+            let watches = registry.watches.lock().unwrap();
+            let mut paths: Vec<PathBuf> = watches
+                .as_ref()
+                .unwrap()
+                .paths
+                .get("example.com")
+                .map(|p| p.iter().cloned().collect())
+                .unwrap_or_default();
+            paths.sort();
+            paths
+        };
+        let release_dir = |id: &str| site_dir.join(RELEASES_DIR).join(id).canonicalize().unwrap();
+
+        assert_eq!(watched(&registry), vec![site_dir.clone(), release_dir("2")]);
+
+        releases::activate(&site_dir, "1").unwrap();
+        registry.refresh_site("example.com").await;
+        assert_eq!(watched(&registry), vec![site_dir.clone(), release_dir("1")]);
+
+        std::fs::remove_dir_all(&site_dir).unwrap();
+        registry.rescan().await.unwrap();
+        assert!(watched(&registry).is_empty());
+    }
+}
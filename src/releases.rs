@@ -0,0 +1,359 @@
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const RELEASES_DIR: &str = "releases";
+pub const CURRENT_LINK: &str = "current";
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Releases {
+    pub current: Option<String>,
+    /// Oldest first
+    pub releases: Vec<String>,
+}
+
+/// Whether a site directory holds releases, rather than the site's files directly
+pub fn is_release_layout(site_dir: &Path) -> bool {
+    let is_pointer =
+        fs::symlink_metadata(site_dir.join(CURRENT_LINK)).is_ok_and(|m| m.file_type().is_symlink());
+    is_pointer && site_dir.join(RELEASES_DIR).is_dir()
+}
+
+fn is_valid_release_id(id: &str) -> bool {
+    !id.is_empty()
+        && !id.starts_with('.')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Order release IDs by when they were created: by timestamp, then by the number of any `-N` suffix
+fn release_order(id: &str) -> (&str, u64) {
+    match id.rsplit_once('-') {
+        Some((timestamp, suffix)) if !timestamp.is_empty() => match suffix.parse() {
+            Ok(n) => (timestamp, n),
+            Err(_) => (id, 0),
+        },
+        _ => (id, 0),
+    }
+}
+
+/// Format a time as `YYYYMMDDhhmmss` (UTC), so release IDs sort in the order they were created
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Convert days since the epoch to a civil date (https://howardhinnant.github.io/date_algorithms.html)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Create an empty directory for a new release, returning its ID.
+///
+/// The directory is claimed with `create_dir`, so two deploys in the same second get different IDs.
+pub fn create_release_dir(site_dir: &Path) -> io::Result<String> {
+    let timestamp = format_timestamp(SystemTime::now());
+    let releases_dir = site_dir.join(RELEASES_DIR);
+    fs::create_dir_all(&releases_dir)?;
+    let mut id = timestamp.clone();
+    let mut suffix = 1;
+    loop {
+        match fs::create_dir(releases_dir.join(&id)) {
+            Ok(()) => return Ok(id),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                id = format!("{}-{}", timestamp, suffix);
+                suffix += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Serialises changes to each site, so deploys, activations and pruning don't race each other
+#[derive(Default)]
+pub struct SiteLocks(Mutex<HashMap<String, Arc<Mutex<()>>>>);
+
+impl SiteLocks {
+    /// Run `f` while holding the lock for `hostname`.
+    ///
+    /// This blocks, so should be run on a thread pool.
+    pub fn with_lock<T>(&self, hostname: &str, f: impl FnOnce() -> T) -> T {
+        let lock = {
+            let mut locks = self.0.lock().unwrap();
+            // Forget locks nobody is holding or waiting for
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(hostname.to_owned()).or_default().clone()
+        };
+        let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        f()
+    }
+}
+
+pub fn list(site_dir: &Path) -> io::Result<Releases> {
+    let mut releases = vec![];
+    for entry in fs::read_dir(site_dir.join(RELEASES_DIR))? {
+        let entry = entry?;
+        if let Some(id) = entry.file_name().to_str() {
+            // Releases still being created are hidden
+            if is_valid_release_id(id) && entry.file_type()?.is_dir() {
+                releases.push(id.to_owned());
+            }
+        }
+    }
+    releases.sort_by(|a, b| release_order(a).cmp(&release_order(b)));
+
+    let current = fs::read_link(site_dir.join(CURRENT_LINK))
+        .ok()
+        .and_then(|target| Some(target.file_name()?.to_str()?.to_owned()));
+
+    Ok(Releases { current, releases })
+}
+
+#[cfg(unix)]
+fn symlink_dir(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_dir(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_dir(target, link)
+}
+
+#[cfg(unix)]
+fn replace_link(new_link: &Path, link: &Path) -> io::Result<()> {
+    fs::rename(new_link, link)
+}
+
+/// Directory symlinks can't be renamed over, so `current` is briefly missing
+#[cfg(windows)]
+fn replace_link(new_link: &Path, link: &Path) -> io::Result<()> {
+    match fs::remove_dir(link) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::rename(new_link, link)
+}
+
+/// Point `current` at a release, atomically replacing the previous pointer
+pub fn activate(site_dir: &Path, id: &str) -> io::Result<()> {
+    if !is_valid_release_id(id) || !site_dir.join(RELEASES_DIR).join(id).is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Release {} not found", id),
+        ));
+    }
+
+    // Relative, so the site directory can be moved around
+    let target = PathBuf::from(RELEASES_DIR).join(id);
+    let temp_link = site_dir.join(format!(".{}-{}", CURRENT_LINK, id));
+    let _ = fs::remove_file(&temp_link);
+    symlink_dir(&target, &temp_link)?;
+    replace_link(&temp_link, &site_dir.join(CURRENT_LINK))
+}
+
+/// Activate the release before the current one, returning its ID if there is one
+pub fn rollback(site_dir: &Path) -> io::Result<Option<String>> {
+    let releases = list(site_dir)?;
+    let previous = releases
+        .current
+        .as_ref()
+        .and_then(|current| releases.releases.iter().position(|id| id == current))
+        .and_then(|i| i.checked_sub(1))
+        .map(|i| releases.releases[i].clone());
+    if let Some(id) = &previous {
+        activate(site_dir, id)?;
+    }
+    Ok(previous)
+}
+
+/// Remove all but the newest `keep` releases, never removing the current release.
+///
+/// Returns the IDs of the removed releases.
+pub fn prune(site_dir: &Path, keep: usize) -> io::Result<Vec<String>> {
+    let releases = list(site_dir)?;
+    let expired = releases.releases.len().saturating_sub(keep);
+    let mut removed = vec![];
+    for id in releases.releases.into_iter().take(expired) {
+        if releases.current.as_ref() == Some(&id) {
+            continue;
+        }
+        fs::remove_dir_all(site_dir.join(RELEASES_DIR).join(&id))?;
+        removed.push(id);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use crate::test_utils::TestDir;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Create `example.com` with a release for each ID, with `current` pointing at the last
+    pub fn create_releases(name: &str, ids: &[&str]) -> TestDir {
+        let root = TestDir::new(&format!("releases-{}", name));
+        let site_dir = root.join("example.com");
+        for id in ids {
+            let release_dir = site_dir.join(RELEASES_DIR).join(id);
+            fs::create_dir_all(&release_dir).unwrap();
+            fs::write(release_dir.join("index.html"), id).unwrap();
+        }
+        activate(&site_dir, ids.last().unwrap()).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "19700101000000");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(1_792_392_790)),
+            "20261019065310"
+        );
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "20000229000000"
+        );
+    }
+
+    #[test]
+    fn test_list_and_activate() {
+        let root = create_releases("list", &["1", "2", "3"]);
+        let site_dir = root.join("example.com");
+        fs::create_dir(site_dir.join(RELEASES_DIR).join(".staging")).unwrap();
+        assert!(is_release_layout(&site_dir));
+        assert_eq!(
+            list(&site_dir).unwrap(),
+            Releases {
+                current: Some(String::from("3")),
+                releases: vec![String::from("1"), String::from("2"), String::from("3")],
+            }
+        );
+
+        activate(&site_dir, "1").unwrap();
+        assert_eq!(
+            fs::read_to_string(site_dir.join(CURRENT_LINK).join("index.html")).unwrap(),
+            "1"
+        );
+        assert_eq!(
+            activate(&site_dir, "4").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(activate(&site_dir, "../..").is_err());
+        assert!(activate(&site_dir, ".staging").is_err());
+    }
+
+    #[test]
+    fn test_rollback() {
+        let root = create_releases("rollback", &["1", "2", "3"]);
+        let site_dir = root.join("example.com");
+        assert_eq!(rollback(&site_dir).unwrap(), Some(String::from("2")));
+        assert_eq!(rollback(&site_dir).unwrap(), Some(String::from("1")));
+        assert_eq!(rollback(&site_dir).unwrap(), None);
+        assert_eq!(list(&site_dir).unwrap().current, Some(String::from("1")));
+    }
+
+    #[test]
+    fn test_suffixed_releases() {
+        let ids = [
+            "20260101000000",
+            "20260101000000-1",
+            "20260101000000-2",
+            "20260101000000-10",
+            "20260101000001",
+        ];
+        let root = create_releases("suffixed", &["20260101000000-10", "20260101000000-2"]);
+        let site_dir = root.join("example.com");
+        for id in ids {
+            fs::create_dir_all(site_dir.join(RELEASES_DIR).join(id)).unwrap();
+        }
+        assert_eq!(list(&site_dir).unwrap().releases, ids);
+
+        activate(&site_dir, "20260101000000-10").unwrap();
+        assert_eq!(
+            rollback(&site_dir).unwrap(),
+            Some(String::from("20260101000000-2"))
+        );
+        activate(&site_dir, "20260101000001").unwrap();
+        assert_eq!(
+            rollback(&site_dir).unwrap(),
+            Some(String::from("20260101000000-10"))
+        );
+    }
+
+    #[test]
+    fn test_prune() {
+        let root = create_releases("prune", &["1", "2", "3", "4"]);
+        let site_dir = root.join("example.com");
+        activate(&site_dir, "1").unwrap();
+        assert_eq!(prune(&site_dir, 2).unwrap(), vec![String::from("2")]);
+        assert_eq!(
+            list(&site_dir).unwrap().releases,
+            vec![String::from("1"), String::from("3"), String::from("4")]
+        );
+        assert!(prune(&site_dir, 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_create_release_dir() {
+        let root = create_releases("new-id", &["1"]);
+        let site_dir = root.join("example.com");
+        let id = create_release_dir(&site_dir).unwrap();
+        assert_eq!(id.len(), 14);
+        assert!(site_dir.join(RELEASES_DIR).join(&id).is_dir());
+        assert_eq!(create_release_dir(&site_dir).unwrap(), format!("{}-1", id));
+    }
+
+    #[test]
+    fn test_site_locks() {
+        let locks = Arc::new(SiteLocks::default());
+        let running = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (locks, running) = (locks.clone(), running.clone());
+                std::thread::spawn(move || {
+                    locks.with_lock("example.com", || {
+                        assert_eq!(running.fetch_add(1, Ordering::SeqCst), 0);
+                        std::thread::sleep(Duration::from_millis(10));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Other sites aren't held up by a site's lock
+        locks.with_lock("example.com", || locks.with_lock("other.com", || {}));
+
+        // Unused locks are forgotten
+        locks.with_lock("other.com", || {});
+        assert_eq!(locks.0.lock().unwrap().len(), 1);
+    }
+}
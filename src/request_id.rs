@@ -0,0 +1,92 @@
+use actix_web::dev::{Payload, RequestHead};
+use actix_web::{Error, FromRequest, HttpRequest};
+use std::fmt;
+use std::future::{ready, Ready};
+use uuid::Uuid;
+
+/// Lowercase, as `HeaderName::from_static` requires
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Identifies a request across Traefik's logs and ours
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+/// Incoming IDs end up in logs, so are kept short and printable
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+impl RequestId {
+    /// The ID the request came with, or a new one
+    pub fn from_head(head: &RequestHead) -> Self {
+        let incoming = head
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid_request_id(id));
+
+        match incoming {
+            Some(id) => RequestId(id.to_owned()),
+            None => RequestId(Uuid::new_v4().to_string()),
+        }
+    }
+
+    /// The ID given to a request when it arrived
+    pub fn of(head: &RequestHead) -> Self {
+        head.extensions()
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId::from_head(head))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(RequestId::of(req.head())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::TestRequest;
+
+    fn get_request_id(header: Option<&str>) -> RequestId {
+        let mut request = TestRequest::get();
+        if let Some(header) = header {
+            request = request.header(REQUEST_ID_HEADER, header);
+        }
+        RequestId::from_head(request.to_http_request().head())
+    }
+
+    #[test]
+    fn test_keeps_incoming_id() {
+        assert_eq!(
+            get_request_id(Some("abc-123")),
+            RequestId(String::from("abc-123"))
+        );
+    }
+
+    #[test]
+    fn test_generates_id() {
+        let request_id = get_request_id(None);
+        assert!(Uuid::parse_str(&request_id.0).is_ok());
+        assert_ne!(request_id, get_request_id(None));
+
+        for invalid in ["", "has space", &"a".repeat(MAX_REQUEST_ID_LENGTH + 1)] {
+            assert_ne!(get_request_id(Some(invalid)).0, invalid);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::check::{check_provider_config, check_sites, has_errors};
+use crate::request_id::RequestId;
+use crate::settings::Settings;
+use actix_web::{web, HttpResponse};
+use log::error;
+use serde_json::json;
+
+pub async fn check(settings: web::Data<Settings>, request_id: RequestId) -> HttpResponse {
+    let problems = match check_sites(settings.registry.sites_root()).await {
+        Ok(mut problems) => check_provider_config(&settings)
+            .await
+            .map(|provider_problems| {
+                problems.extend(provider_problems);
+                problems
+            }),
+        Err(e) => Err(e),
+    };
+    match problems {
+        Ok(problems) => HttpResponse::Ok().json(json!({
+            "ok": !has_errors(&problems),
+            "problems": problems,
+        })),
+        Err(e) => {
+            error!("[{}] Failed to check sites: {}", request_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::configure_app;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::test_utils::{auth_credentials, get_test_settings};
+    use actix_web::http::header;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_check() {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/check", INTERNAL_ROUTE_PREFIX))
+            .header(header::AUTHORIZATION, auth_credentials())
+            .to_request();
+        let response: Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(
+            response,
+            json!({
+                "ok": true,
+                "problems": [{
+                    "severity": "warning",
+                    "path": "stray_file.txt",
+                    "problem": "Not a directory, so not served"
+                }]
+            })
+        );
+    }
+}
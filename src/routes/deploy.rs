@@ -0,0 +1,177 @@
+use crate::deploy::{deploy, remove, DeployError, DeployLimits};
+use crate::request_id::RequestId;
+use crate::routes::releases::prune_after_deploy;
+use crate::settings::Settings;
+use crate::site::is_valid_hostname;
+use actix_web::error::BlockingError;
+use actix_web::{web, HttpResponse};
+use log::{error, info};
+
+pub async fn deploy_site(
+    settings: web::Data<Settings>,
+    hostname: web::Path<String>,
+    body: web::Bytes,
+    request_id: RequestId,
+) -> HttpResponse {
+    let hostname = hostname.into_inner();
+    if !is_valid_hostname(&hostname) {
+        return HttpResponse::BadRequest().body(format!("Invalid hostname {}", hostname));
+    }
+
+    let sites_root = settings.registry.sites_root().to_path_buf();
+    let limits = DeployLimits {
+        max_size: settings.deploy_max_extracted_size,
+        max_entries: settings.deploy_max_entries,
+    };
+    let site_locks = settings.site_locks.clone();
+    let deploy_hostname = hostname.clone();
+    let result = web::block(move || {
+        site_locks.with_lock(&deploy_hostname, || {
+            deploy(&sites_root, &deploy_hostname, &body, limits)
+        })
+    })
+    .await;
+
+    match result {
+        Ok(deployment) => {
+            settings.registry.refresh_site(&hostname).await;
+            info!(
+                "[{}] Deployed {} release {} ({} files, {} bytes)",
+                request_id, hostname, deployment.release, deployment.files, deployment.bytes
+            );
+            prune_after_deploy(&settings, &hostname, &request_id).await;
+            if deployment.replaced {
+                HttpResponse::Ok().json(deployment)
+            } else {
+                HttpResponse::Created().json(deployment)
+            }
+        }
+        Err(BlockingError::Error(DeployError::Invalid(problem))) => {
+            HttpResponse::BadRequest().body(problem)
+        }
+        Err(BlockingError::Error(DeployError::TooLarge)) => {
+            HttpResponse::PayloadTooLarge().finish()
+        }
+        Err(BlockingError::Error(DeployError::Io(e))) => {
+            error!("[{}] Failed to deploy {}: {}", request_id, hostname, e);
+            HttpResponse::InternalServerError().finish()
+        }
+        Err(BlockingError::Canceled) => {
+            error!("[{}] Deploy of {} was cancelled", request_id, hostname);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn remove_site(
+    settings: web::Data<Settings>,
+    hostname: web::Path<String>,
+    request_id: RequestId,
+) -> HttpResponse {
+    let hostname = hostname.into_inner();
+    if !is_valid_hostname(&hostname) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let sites_root = settings.registry.sites_root().to_path_buf();
+    let site_locks = settings.site_locks.clone();
+    let remove_hostname = hostname.clone();
+    let result = web::block(move || {
+        site_locks.with_lock(&remove_hostname, || remove(&sites_root, &remove_hostname))
+    })
+    .await;
+    match result {
+        Ok(true) => {
+            settings.registry.refresh_site(&hostname).await;
+            info!("[{}] Removed {}", request_id, hostname);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("[{}] Failed to remove {}: {:?}", request_id, hostname, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::configure_app;
+    use crate::deploy::tests::get_tar_gz;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::test_utils::{auth_credentials, TestDir};
+    use actix_web::http::{header, Method};
+    use actix_web::{test, App};
+
+    fn get_request(method: Method, hostname: &str) -> test::TestRequest {
+        test::TestRequest::with_uri(&format!("/{}/sites/{}", INTERNAL_ROUTE_PREFIX, hostname))
+            .method(method)
+            .header(header::AUTHORIZATION, auth_credentials())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_and_remove() {
+        let root = TestDir::new("deploy-route-deploy");
+        let settings = root.settings();
+        let registry = settings.registry.clone();
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings))).await;
+
+        let request = get_request(Method::PUT, "example.com")
+            .set_payload(get_tar_gz(&[("index.html", "deployed")]))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 201);
+        assert!(registry.get("example.com", "").await.is_some());
+
+        let request = test::TestRequest::get()
+            .uri("/")
+            .header(header::HOST, "example.com")
+            .to_request();
+        assert_eq!(test::read_response(&mut app, request).await, "deployed");
+
+        let request = get_request(Method::PUT, "example.com")
+            .set_payload(get_tar_gz(&[("index.html", "redeployed")]))
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 200);
+
+        let request = test::TestRequest::get()
+            .uri("/")
+            .header(header::HOST, "example.com")
+            .to_request();
+        assert_eq!(test::read_response(&mut app, request).await, "redeployed");
+
+        let request = get_request(Method::DELETE, "example.com").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 204);
+        assert!(registry.get("example.com", "").await.is_none());
+
+        let request = get_request(Method::DELETE, "example.com").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_deploy_rejected() {
+        let root = TestDir::new("deploy-route-rejected");
+        let settings = root.settings();
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings))).await;
+
+        let request = get_request(Method::PUT, ".hidden")
+            .set_payload(get_tar_gz(&[("index.html", "")]))
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 400);
+
+        let request = get_request(Method::PUT, "example.com")
+            .set_payload("not an archive")
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 400);
+
+        let request = test::TestRequest::put()
+            .uri(&format!("/{}/sites/example.com", INTERNAL_ROUTE_PREFIX))
+            .header(header::AUTHORIZATION, "Bearer provider-secret")
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 403);
+
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+    }
+}
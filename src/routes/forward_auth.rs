@@ -0,0 +1,205 @@
+use crate::access_policy::check_access;
+use crate::request_id::RequestId;
+use crate::routes::serve::{get_hostname, normalize_path};
+use crate::settings::Settings;
+use crate::site::is_valid_hostname;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::info;
+use serde_derive::Deserialize;
+
+const X_FORWARDED_URI: &str = "X-Forwarded-Uri";
+const X_FORWARDED_USER: &str = "X-Forwarded-User";
+
+#[derive(Deserialize)]
+pub struct ForwardAuthQuery {
+    /// Use this site's rules, rather than those of the forwarded host
+    site: Option<String>,
+}
+
+/// For Traefik's `forwardAuth` middleware, which asks about each request before sending it on
+pub async fn forward_auth(
+    req: HttpRequest,
+    settings: web::Data<Settings>,
+    query: web::Query<ForwardAuthQuery>,
+    request_id: RequestId,
+) -> HttpResponse {
+    // Anyone else could claim to be asking about any request
+    let is_proxied = settings.trusted_proxies.is_proxied(req.head());
+    let hostname = match &query.site {
+        Some(site) if is_proxied => site.clone(),
+        _ => get_hostname(&req, &settings.trusted_proxies),
+    };
+    if !is_valid_hostname(&hostname) {
+        return HttpResponse::NotFound().finish();
+    }
+    let uri = req
+        .headers()
+        .get(X_FORWARDED_URI)
+        .filter(|_| is_proxied)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("/");
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let url_path = match normalize_path(path) {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let url_path = url_path.as_str();
+
+    let site = match settings.site_from_hostname(&hostname, url_path).await {
+        Some(s) => s,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let site_path = site.strip_path_prefix(url_path).unwrap_or_default();
+    let client_ip = settings.trusted_proxies.client_ip(req.head());
+
+    let is_signed = || settings.is_signed(&hostname, &format!("/{}", url_path), query);
+
+    match check_access(&site.config, site_path, client_ip, req.headers(), is_signed) {
+        Ok(Some(user)) => HttpResponse::Ok().header(X_FORWARDED_USER, user).finish(),
+        Ok(None) => HttpResponse::Ok().finish(),
+        Err(denied) => {
+            info!(
+                "[{}] Refused {}/{} for {}: {:?}",
+                request_id,
+                hostname,
+                url_path,
+                client_ip.map_or_else(|| String::from("unknown"), |ip| ip.to_string()),
+                denied
+            );
+            denied.into_response(&hostname)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::configure_app;
+    use crate::client_ip::TrustedProxies;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::site_config::CONFIG_FILENAME;
+    use crate::test_utils::TestDir;
+    use actix_web::http::header;
+    use actix_web::{test, App};
+    use actix_web_httpauth::headers::authorization::Basic;
+
+    #[tokio::test]
+    async fn test_forward_auth() {
+        let root = TestDir::new("forward-auth");
+        root.write(
+            &format!("example.com/{}", CONFIG_FILENAME),
+            r#"
+            [[paths]]
+            prefix = "api/"
+            allow_ips = ["10.8.0.0/16"]
+            [[paths.credentials]]
+            username = "admin"
+            password_sha256 = "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8"
+            "#,
+        );
+
+        let mut settings = root.settings();
+        settings.trusted_proxies = TrustedProxies::parse(&[String::from("10.0.0.1")]).unwrap();
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings))).await;
+        let get_request = |host: &str, uri: &str, client: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/{}/forward-auth", INTERNAL_ROUTE_PREFIX))
+                .header(header::HOST, "traefik-pages")
+                .header("X-Forwarded-Host", host)
+                .header("X-Forwarded-Uri", uri)
+                .header("X-Forwarded-For", client)
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+        };
+
+        let response = test::call_service(
+            &mut app,
+            get_request("example.com", "/index.html?a=b", "198.51.100.1").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+
+        let response = test::call_service(
+            &mut app,
+            get_request("example.com", "/api/users", "198.51.100.1").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 403);
+
+        let response = test::call_service(
+            &mut app,
+            get_request("example.com", "/api/users", "10.8.1.1").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            r#"Basic realm="example.com""#
+        );
+
+        // Dot segments and missing slashes don't get around the path's rules
+        for uri in ["/./api/users", "/docs/../api/users", "//api/users", "/api"] {
+            let response = test::call_service(
+                &mut app,
+                get_request("example.com", uri, "10.8.1.1").to_request(),
+            )
+            .await;
+            assert_eq!(response.status(), 401, "{}", uri);
+        }
+        let response = test::call_service(
+            &mut app,
+            get_request("example.com", "/../api/users", "10.8.1.1").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 404);
+
+        let request = get_request("example.com", "/api/users", "10.8.1.1")
+            .header(header::AUTHORIZATION, Basic::new("admin", Some("password")))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("X-Forwarded-User").unwrap(), "admin");
+
+        let response = test::call_service(
+            &mut app,
+            get_request("unknown.com", "/", "10.8.1.1").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 404);
+
+        // Another service can share a site's rules
+        let request = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/forward-auth?site=example.com",
+                INTERNAL_ROUTE_PREFIX
+            ))
+            .header("X-Forwarded-Host", "api.example.com")
+            .header("X-Forwarded-Uri", "/api/users")
+            .header("X-Forwarded-For", "198.51.100.1")
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 403);
+
+        // Only trusted proxies can say which site and path they're asking about
+        let request = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/forward-auth?site=example.com",
+                INTERNAL_ROUTE_PREFIX
+            ))
+            .header(header::HOST, "traefik-pages")
+            .header("X-Forwarded-Host", "example.com")
+            .header("X-Forwarded-Uri", "/api/users")
+            .peer_addr("192.0.2.1:1234".parse().unwrap())
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 404);
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/forward-auth", INTERNAL_ROUTE_PREFIX))
+            .header(header::HOST, "example.com")
+            .header("X-Forwarded-Uri", "/api/users")
+            .peer_addr("10.8.1.1:1234".parse().unwrap())
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 200);
+    }
+}
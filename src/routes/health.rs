@@ -1,18 +1,177 @@
-use actix_web::HttpResponse;
+use crate::settings::Settings;
+use crate::VERSION;
+use actix_web::{web, HttpResponse};
+use serde_derive::Serialize;
 
+/// Liveness: the process is up and handling requests
 pub async fn health() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
+#[derive(Serialize, Debug, PartialEq)]
+struct FailingCheck {
+    check: &'static str,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    version: &'static str,
+    /// Seconds since startup
+    uptime: u64,
+    sites: usize,
+    failing: Vec<FailingCheck>,
+}
+
+/// Check the sites can be served, going by the registry's last scan. This doesn't touch the disk,
+/// as anyone can ask, and errors don't include paths.
+fn get_failing_checks(settings: &Settings) -> (usize, Vec<FailingCheck>) {
+    let registry = &settings.registry;
+    let mut failing = vec![];
+
+    if let Some(kind) = registry.last_rescan_error() {
+        failing.push(FailingCheck {
+            check: "sites_root",
+            error: format!("Failed to scan the sites root: {}", kind),
+        });
+    } else if !registry.is_loaded() {
+        failing.push(FailingCheck {
+            check: "sites_root",
+            error: String::from("The sites root hasn't been scanned yet"),
+        });
+    }
+
+    let (valid_sites, config_errors) = registry.site_counts();
+    if valid_sites == 0 {
+        failing.push(FailingCheck {
+            check: "sites",
+            error: String::from("No sites found"),
+        });
+    }
+    if config_errors > settings.ready_max_config_errors {
+        failing.push(FailingCheck {
+            check: "config_errors",
+            error: format!(
+                "{} sites have invalid config, more than the maximum of {}",
+                config_errors, settings.ready_max_config_errors
+            ),
+        });
+    }
+
+    (valid_sites, failing)
+}
+
+/// Readiness: the sites can be served
+pub async fn ready(settings: web::Data<Settings>) -> HttpResponse {
+    let (sites, failing) = get_failing_checks(&settings);
+    let readiness = Readiness {
+        ready: failing.is_empty(),
+        version: VERSION,
+        uptime: settings.started.elapsed().as_secs(),
+        sites,
+        failing,
+    };
+
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use actix_web::http::StatusCode;
 
+    use crate::app::configure_app;
+    use crate::registry::SiteRegistry;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::test_utils::{get_test_settings, TestDir};
+    use actix_web::{test, App};
+    use serde_json::Value;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
     #[tokio::test]
     async fn test_health() {
         let response = health().await;
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_health_without_auth() {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/health", INTERNAL_ROUTE_PREFIX))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let settings = get_test_settings();
+        settings.registry.rescan().await.unwrap();
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings))).await;
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/ready", INTERNAL_ROUTE_PREFIX))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let readiness: Value = test::read_body_json(response).await;
+        assert_eq!(readiness["ready"], true);
+        assert_eq!(readiness["version"], VERSION);
+        assert_eq!(readiness["sites"], 5);
+        assert_eq!(readiness["failing"], Value::Array(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_not_ready() {
+        let mut settings = get_test_settings();
+        settings.registry = Arc::new(SiteRegistry::new(PathBuf::from(
+            "/nonexistent/traefik-pages",
+        )));
+        let (sites, failing) = get_failing_checks(&settings);
+        assert_eq!(sites, 0);
+        assert_eq!(failing[0].check, "sites_root");
+
+        assert!(settings.registry.rescan().await.is_err());
+        let (_, failing) = get_failing_checks(&settings);
+        assert_eq!(failing[0].check, "sites_root");
+        assert!(!failing[0].error.contains("nonexistent"));
+
+        let root = TestDir::new("not-ready");
+        root.write("example.com/pages.toml", "dir_index = 1");
+        settings.registry = root.settings().registry;
+        settings.registry.rescan().await.unwrap();
+        let (_, failing) = get_failing_checks(&settings);
+        assert_eq!(
+            failing.iter().map(|f| f.check).collect::<Vec<&str>>(),
+            vec!["sites", "config_errors"]
+        );
+
+        let response = ready(web::Data::new(settings)).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_ready_uses_registry() {
+        let root = TestDir::new("ready-registry");
+        root.write("example.com/index.html", "");
+        let settings = root.settings();
+        settings.registry.rescan().await.unwrap();
+        assert!(get_failing_checks(&settings).1.is_empty());
+
+        // Sites appearing or disappearing on disk aren't seen until the next scan
+        std::fs::remove_dir_all(root.join("example.com")).unwrap();
+        assert_eq!(get_failing_checks(&settings).0, 1);
+        settings.registry.rescan().await.unwrap();
+        assert_eq!(get_failing_checks(&settings).1[0].check, "sites");
+    }
 }
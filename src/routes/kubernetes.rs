@@ -0,0 +1,82 @@
+use crate::kubernetes::{get_manifests, to_yaml, ManifestKind};
+use crate::settings::Settings;
+use actix_web::{web, HttpResponse};
+use serde_derive::Deserialize;
+use std::io;
+
+#[derive(Deserialize)]
+pub struct KubernetesQuery {
+    kind: Option<String>,
+}
+
+pub async fn kubernetes_manifests(
+    settings: web::Data<Settings>,
+    query: web::Query<KubernetesQuery>,
+) -> HttpResponse {
+    let kind = match query.kind.as_deref() {
+        Some(name) => match ManifestKind::from_name(name) {
+            Some(k) => k,
+            None => return HttpResponse::BadRequest().finish(),
+        },
+        None => ManifestKind::IngressRoute,
+    };
+
+    match get_manifests(&settings, kind)
+        .await
+        .and_then(|manifests| to_yaml(&manifests))
+    {
+        Ok(yaml) => HttpResponse::Ok()
+            .content_type("application/yaml")
+            .body(yaml),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            HttpResponse::Conflict().body(e.to_string())
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::configure_app;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::test_utils::{auth_credentials, get_test_settings};
+    use actix_web::http::header;
+    use actix_web::{test, App};
+
+    async fn get_manifests_body(query: &str) -> (u16, String) {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/kubernetes{}", INTERNAL_ROUTE_PREFIX, query))
+            .header(header::AUTHORIZATION, auth_credentials())
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        let status = response.status().as_u16();
+        let body = test::read_body(response).await;
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_ingress_routes() {
+        let (status, body) = get_manifests_body("").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("kind: IngressRoute"));
+        assert!(!body.contains("kind: Ingress\n"));
+    }
+
+    #[tokio::test]
+    async fn test_ingresses() {
+        let (status, body) = get_manifests_body("?kind=ingress").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("kind: Ingress\n"));
+        assert!(body.contains("kind: Middleware"));
+        assert!(!body.contains("kind: IngressRoute"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_kind() {
+        let (status, _) = get_manifests_body("?kind=gateway").await;
+        assert_eq!(status, 400);
+    }
+}
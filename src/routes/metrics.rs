@@ -0,0 +1,69 @@
+use crate::settings::Settings;
+use actix_web::{web, HttpResponse};
+
+pub async fn metrics(settings: web::Data<Settings>) -> HttpResponse {
+    let (sites, config_errors) = match (
+        settings.discover_sites().await,
+        settings.registry.config_error_count().await,
+    ) {
+        (Ok(sites), Ok(config_errors)) => (sites.len(), config_errors),
+        _ => return HttpResponse::InternalServerError().finish(),
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(settings.metrics.render(
+            sites,
+            config_errors,
+            settings.registry.last_discovery_duration(),
+            settings.rate_limiter.tracked(),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::configure_app;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::test_utils::{auth_credentials, get_test_settings};
+    use actix_web::http::header;
+    use actix_web::{test, App};
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let mut settings = get_test_settings();
+        settings.deny_prefixes = vec![String::from(".git")];
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings))).await;
+        for path in ["/", "/missing.html", "/.git/config"] {
+            let request = test::TestRequest::get()
+                .uri(path)
+                .header(header::HOST, "localhost")
+                .to_request();
+            test::call_service(&mut app, request).await;
+        }
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/metrics", INTERNAL_ROUTE_PREFIX))
+            .header(header::AUTHORIZATION, auth_credentials())
+            .to_request();
+        let body = test::read_response(&mut app, request).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        for line in [
+            "traefik_pages_requests_total{site=\"localhost\",status=\"2xx\"} 1",
+            "traefik_pages_requests_total{site=\"localhost\",status=\"4xx\"} 2",
+            "traefik_pages_response_bytes_total{site=\"localhost\"} 16",
+            "traefik_pages_not_found_total{site=\"localhost\"} 2",
+            "traefik_pages_denied_total{site=\"localhost\"} 1",
+            "traefik_pages_sites 5",
+            "traefik_pages_config_errors 0",
+        ] {
+            assert!(body.lines().any(|l| l == line), "Missing {}", line);
+        }
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/metrics", INTERNAL_ROUTE_PREFIX))
+            .header(header::AUTHORIZATION, "Bearer provider-secret")
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 403);
+    }
+}
@@ -1,23 +1,176 @@
-use actix_web::{web, Scope};
+use actix_web::dev::{HttpServiceFactory, Service};
+use actix_web::{web, HttpRequest, HttpResponse, Scope};
+use log::info;
+mod check;
+mod deploy;
+mod forward_auth;
 mod health;
+mod kubernetes;
+mod metrics;
+mod proxy;
+mod releases;
 mod serve;
+mod signed_urls;
 mod sites;
 mod traefik;
-use crate::auth::BasicAuthGuard;
+use crate::auth::{authenticate, authorize, TokenGuard, TokenScope, AUDIT_LOG_TARGET};
+use crate::request_id::RequestId;
 use crate::settings::Settings;
 
 pub const INTERNAL_ROUTE_PREFIX: &str = ".traefik-pages";
 
-fn get_internal_routes(settings: &Settings) -> Scope {
+/// The request was authenticated, but no route it's allowed to use matched
+async fn scope_not_granted(req: HttpRequest, settings: web::Data<Settings>) -> HttpResponse {
+    match authenticate(&settings.api_tokens, req.head()) {
+        // Admin tokens can use every route, so none must exist
+        Some(token) if token.has_scope(TokenScope::Admin) => HttpResponse::NotFound().finish(),
+        _ => HttpResponse::Forbidden().finish(),
+    }
+}
+
+fn get_internal_routes(settings: &Settings) -> impl HttpServiceFactory {
+    let tokens = settings.api_tokens.clone();
+    let throttle = settings.auth_throttle.clone();
+    let trusted_proxies = settings.trusted_proxies.clone();
+    let requires = |scope| TokenGuard::new(&settings.api_tokens, scope);
+
     web::scope(INTERNAL_ROUTE_PREFIX)
-        .guard(BasicAuthGuard::new(&settings.auth_password))
-        .route("/health", web::route().to(health::health))
-        .route("/sites", web::get().to(sites::sites_list))
-        .route("/provider", web::get().to(traefik::traefik_provider))
+        .data(traefik::ProviderCache::default())
+        .route(
+            "/sites",
+            web::get()
+                .guard(requires(TokenScope::SitesRead))
+                .to(sites::sites_list),
+        )
+        .service(
+            web::resource("/sites/{hostname}")
+                .app_data(web::PayloadConfig::new(settings.deploy_max_size))
+                .route(
+                    web::get()
+                        .guard(requires(TokenScope::SitesRead))
+                        .to(sites::site_detail),
+                )
+                .route(
+                    web::put()
+                        .guard(requires(TokenScope::DeployWrite))
+                        .to(deploy::deploy_site),
+                )
+                .route(
+                    web::delete()
+                        .guard(requires(TokenScope::DeployWrite))
+                        .to(deploy::remove_site),
+                )
+                .default_service(web::route().to(scope_not_granted)),
+        )
+        .route(
+            "/check",
+            web::get()
+                .guard(requires(TokenScope::SitesRead))
+                .to(check::check),
+        )
+        .route(
+            "/sites/{hostname}/releases",
+            web::get()
+                .guard(requires(TokenScope::SitesRead))
+                .to(releases::list_releases),
+        )
+        .route(
+            "/sites/{hostname}/releases/prune",
+            web::post()
+                .guard(requires(TokenScope::DeployWrite))
+                .to(releases::prune_releases),
+        )
+        .route(
+            "/sites/{hostname}/releases/{release}/activate",
+            web::post()
+                .guard(requires(TokenScope::DeployWrite))
+                .to(releases::activate_release),
+        )
+        .route(
+            "/sites/{hostname}/signed-urls",
+            web::post()
+                .guard(requires(TokenScope::UrlsSign))
+                .to(signed_urls::sign_url),
+        )
+        .route(
+            "/sites/{hostname}/rollback",
+            web::post()
+                .guard(requires(TokenScope::DeployWrite))
+                .to(releases::rollback_site),
+        )
+        .route(
+            "/metrics",
+            web::get()
+                .guard(requires(TokenScope::MetricsRead))
+                .to(metrics::metrics),
+        )
+        .route(
+            "/provider",
+            web::get()
+                .guard(requires(TokenScope::ProviderRead))
+                .to(traefik::traefik_provider),
+        )
+        .route(
+            "/provider/diagnostics",
+            web::get()
+                .guard(requires(TokenScope::ProviderRead))
+                .to(traefik::provider_diagnostics),
+        )
+        .route(
+            "/kubernetes",
+            web::get()
+                .guard(requires(TokenScope::ProviderRead))
+                .to(kubernetes::kubernetes_manifests),
+        )
+        .route(
+            "/config/{backend}",
+            web::get()
+                .guard(requires(TokenScope::ProviderRead))
+                .to(proxy::backend_config),
+        )
+        .default_service(web::route().to(scope_not_granted))
+        // Every request under the prefix must be authenticated, rather than falling through to sites
+        .wrap_fn(move |req, srv| {
+            let call = match authorize(&req, &tokens, &throttle, &trusted_proxies) {
+                Ok(token_name) => Ok((token_name, req.method().clone(), srv.call(req))),
+                Err(response) => Err(req.into_response(response)),
+            };
+            async move {
+                let (token_name, method, response) = match call {
+                    Ok(c) => c,
+                    Err(rejected) => return Ok(rejected),
+                };
+                let response = response.await?;
+                info!(
+                    target: AUDIT_LOG_TARGET,
+                    "[{}] {} {} by {}: {}",
+                    RequestId::of(response.request().head()),
+                    method,
+                    response.request().path(),
+                    token_name,
+                    response.status().as_u16()
+                );
+                Ok(response)
+            }
+        })
 }
 
 pub fn get_routes(settings: &Settings) -> Scope {
     web::scope("")
+        // Health checks don't need credentials, so load balancers can use them
+        .route(
+            &format!("/{}/health", INTERNAL_ROUTE_PREFIX),
+            web::route().to(health::health),
+        )
+        .route(
+            &format!("/{}/ready", INTERNAL_ROUTE_PREFIX),
+            web::route().to(health::ready),
+        )
+        // Traefik passes on the client's own Authorization header, so there's no room for a token
+        .route(
+            &format!("/{}/forward-auth", INTERNAL_ROUTE_PREFIX),
+            web::route().to(forward_auth::forward_auth),
+        )
         .service(get_internal_routes(settings))
         // These must go at the end
         .route("/{path:.*}", web::get().to(serve::serve_file))
@@ -0,0 +1,72 @@
+use crate::proxy::{get_backend_config, Backend};
+use crate::settings::Settings;
+use actix_web::{web, HttpResponse};
+use std::io;
+
+pub async fn backend_config(
+    settings: web::Data<Settings>,
+    backend: web::Path<String>,
+) -> HttpResponse {
+    let backend = match Backend::from_name(&backend) {
+        Some(b) => b,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    match get_backend_config(&settings, backend).await {
+        Ok(config) => HttpResponse::Ok()
+            .content_type(backend.content_type())
+            .body(config),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            HttpResponse::Conflict().body(e.to_string())
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::configure_app;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::test_utils::{auth_credentials, get_test_settings};
+    use actix_web::http::header;
+    use actix_web::{test, App};
+
+    async fn get_config_response(backend: &str) -> actix_web::dev::ServiceResponse {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let request = test::TestRequest::get()
+            .uri(&format!("/{}/config/{}", INTERNAL_ROUTE_PREFIX, backend))
+            .header(header::AUTHORIZATION, auth_credentials())
+            .to_request();
+        test::call_service(&mut app, request).await
+    }
+
+    #[tokio::test]
+    async fn test_caddy() {
+        let response = get_config_response("caddy").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body = test::read_body(response).await;
+        assert!(serde_json::from_slice::<serde_json::Value>(&body).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_nginx() {
+        let response = get_config_response("nginx").await;
+        assert_eq!(response.status(), 200);
+        let body = test::read_body(response).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("server_name \"localhost\";"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_backend() {
+        let response = get_config_response("haproxy").await;
+        assert_eq!(response.status(), 404);
+    }
+}
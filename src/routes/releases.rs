@@ -0,0 +1,228 @@
+use crate::releases::{activate, is_release_layout, list, prune, rollback};
+use crate::request_id::RequestId;
+use crate::settings::Settings;
+use crate::site::is_valid_hostname;
+use actix_web::error::BlockingError;
+use actix_web::{web, HttpResponse};
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::io;
+use std::path::Path;
+
+/// Run `f` against a site's directory on the thread pool, holding the site's lock, so long as the site uses releases
+async fn with_site_dir<T, F>(
+    settings: &Settings,
+    hostname: &str,
+    request_id: &RequestId,
+    f: F,
+) -> Result<T, HttpResponse>
+where
+    F: FnOnce(&Path) -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    if !is_valid_hostname(hostname) {
+        return Err(HttpResponse::NotFound().finish());
+    }
+    let site_dir = settings.registry.sites_root().join(hostname);
+    let site_locks = settings.site_locks.clone();
+    let lock_hostname = hostname.to_owned();
+    let result = web::block(move || {
+        site_locks.with_lock(&lock_hostname, || {
+            if !is_release_layout(&site_dir) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Site doesn't use releases",
+                ));
+            }
+            f(&site_dir)
+        })
+    })
+    .await;
+
+    match result {
+        Ok(r) => Ok(r),
+        Err(BlockingError::Error(e)) if e.kind() == io::ErrorKind::NotFound => {
+            Err(HttpResponse::NotFound().finish())
+        }
+        Err(e) => {
+            error!(
+                "[{}] Failed to manage releases of {}: {:?}",
+                request_id, hostname, e
+            );
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+pub async fn list_releases(
+    settings: web::Data<Settings>,
+    hostname: web::Path<String>,
+    request_id: RequestId,
+) -> HttpResponse {
+    match with_site_dir(&settings, &hostname, &request_id, list).await {
+        Ok(releases) => HttpResponse::Ok().json(releases),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReleasePath {
+    hostname: String,
+    release: String,
+}
+
+pub async fn activate_release(
+    settings: web::Data<Settings>,
+    path: web::Path<ReleasePath>,
+    request_id: RequestId,
+) -> HttpResponse {
+    let release = path.release.clone();
+    let result = with_site_dir(&settings, &path.hostname, &request_id, move |site_dir| {
+        activate(site_dir, &release)
+    })
+    .await;
+
+    match result {
+        Ok(()) => {
+            settings.registry.refresh_site(&path.hostname).await;
+            info!(
+                "[{}] Activated release {} of {}",
+                request_id, path.release, path.hostname
+            );
+            HttpResponse::Ok().json(json!({ "current": path.release }))
+        }
+        Err(response) => response,
+    }
+}
+
+pub async fn rollback_site(
+    settings: web::Data<Settings>,
+    hostname: web::Path<String>,
+    request_id: RequestId,
+) -> HttpResponse {
+    match with_site_dir(&settings, &hostname, &request_id, rollback).await {
+        Ok(Some(release)) => {
+            settings.registry.refresh_site(&hostname).await;
+            info!(
+                "[{}] Rolled {} back to release {}",
+                request_id, hostname, release
+            );
+            HttpResponse::Ok().json(json!({ "current": release }))
+        }
+        Ok(None) => HttpResponse::Conflict().body("No earlier release to roll back to"),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PruneQuery {
+    keep: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Pruned {
+    removed: Vec<String>,
+}
+
+pub async fn prune_releases(
+    settings: web::Data<Settings>,
+    hostname: web::Path<String>,
+    query: web::Query<PruneQuery>,
+    request_id: RequestId,
+) -> HttpResponse {
+    let keep = query.keep.unwrap_or(settings.release_retention);
+    match with_site_dir(&settings, &hostname, &request_id, move |site_dir| {
+        prune(site_dir, keep)
+    })
+    .await
+    {
+        Ok(removed) => HttpResponse::Ok().json(Pruned { removed }),
+        Err(response) => response,
+    }
+}
+
+/// Remove releases beyond the retention count after a deploy, which mustn't fail the deploy
+pub async fn prune_after_deploy(settings: &Settings, hostname: &str, request_id: &RequestId) {
+    let keep = settings.release_retention;
+    if let Err(response) = with_site_dir(settings, hostname, request_id, move |site_dir| {
+        prune(site_dir, keep)
+    })
+    .await
+    {
+        error!(
+            "[{}] Failed to prune releases of {}: {}",
+            request_id,
+            hostname,
+            response.status()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::configure_app;
+    use crate::releases::tests::create_releases;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::test_utils::auth_credentials;
+    use actix_web::http::{header, Method};
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    fn get_request(method: Method, path: &str) -> test::TestRequest {
+        test::TestRequest::with_uri(&format!(
+            "/{}/sites/example.com{}",
+            INTERNAL_ROUTE_PREFIX, path
+        ))
+        .method(method)
+        .header(header::AUTHORIZATION, auth_credentials())
+    }
+
+    #[tokio::test]
+    async fn test_manage_releases() {
+        let root = create_releases("routes", &["1", "2", "3"]);
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, root.settings())))
+                .await;
+
+        let request = get_request(Method::GET, "/releases").to_request();
+        let releases: Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(
+            releases,
+            json!({"current": "3", "releases": ["1", "2", "3"]})
+        );
+
+        let request = get_request(Method::POST, "/rollback").to_request();
+        let response: Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(response, json!({"current": "2"}));
+        let request = test::TestRequest::get()
+            .uri("/")
+            .header(header::HOST, "example.com")
+            .to_request();
+        assert_eq!(test::read_response(&mut app, request).await, "2");
+
+        let request = get_request(Method::POST, "/releases/1/activate").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 200);
+        let request = get_request(Method::POST, "/rollback").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 409);
+        let request = get_request(Method::POST, "/releases/4/activate").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 404);
+
+        let request = get_request(Method::POST, "/releases/prune?keep=1").to_request();
+        let response: Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(response, json!({"removed": ["2"]}));
+
+        let request = test::TestRequest::with_uri(&format!(
+            "/{}/sites/missing.com/releases",
+            INTERNAL_ROUTE_PREFIX
+        ))
+        .header(header::AUTHORIZATION, auth_credentials())
+        .to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 404);
+
+        let request = get_request(Method::POST, "/rollback")
+            .header(header::AUTHORIZATION, "Bearer provider-secret")
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 403);
+    }
+}
@@ -1,44 +1,169 @@
+use crate::access_log::ServedFile;
+use crate::access_policy::check_access;
+use crate::client_ip::TrustedProxies;
+use crate::metrics::SiteLabel;
+use crate::request_id::RequestId;
 use crate::settings::Settings;
-use crate::site::is_valid_hostname;
+use crate::site::{is_valid_hostname, Site};
 use actix_files::NamedFile;
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::header::{HOST, RETRY_AFTER};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use log::error;
 
-fn get_hostname(request: &HttpRequest) -> String {
+/// The request's hostname, from `X-Forwarded-Host` if it was forwarded by a trusted proxy
+pub fn get_hostname(request: &HttpRequest, trusted_proxies: &TrustedProxies) -> String {
     let conn_info = request.connection_info();
-    let host = conn_info.host();
+    let host = if trusted_proxies.is_proxied(request.head()) {
+        conn_info.host()
+    } else {
+        request
+            .headers()
+            .get(HOST)
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| request.uri().host())
+            .unwrap_or_default()
+    };
     match host.find(':') {
         None => host.into(),
         Some(i) => host.split_at(i).0.into(),
     }
 }
 
-pub async fn serve_file(req: HttpRequest, settings: web::Data<Settings>) -> HttpResponse {
-    let hostname = get_hostname(&req);
+/// Collapse `.`, `..` and empty segments in a URL path, so rules see the path which will be
+/// served. The result has no leading `/`. Paths escaping the root give `None`.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            s => segments.push(s),
+        }
+    }
+    let mut normalized = segments.join("/");
+    let is_dir = matches!(path.rsplit('/').next(), Some("" | "." | ".."));
+    if is_dir && !normalized.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+pub async fn serve_file(
+    req: HttpRequest,
+    settings: web::Data<Settings>,
+    request_id: RequestId,
+) -> HttpResponse {
+    let hostname = get_hostname(&req, &settings.trusted_proxies);
     if !is_valid_hostname(&hostname) {
         return HttpResponse::NotFound().finish();
     }
-    let site = match settings.site_from_hostname(&hostname).await {
+    let url_path = match normalize_path(req.path()) {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let url_path = url_path.as_str();
+
+    let site = match settings.site_from_hostname(&hostname, url_path).await {
         Some(s) => s,
         None => return HttpResponse::NotFound().finish(),
     };
+    req.extensions_mut().insert(SiteLabel(hostname.clone()));
+
+    // Nested sites' deny prefixes and paths are relative to the site, not the hostname
+    let site_path = site.strip_path_prefix(url_path).unwrap_or_default();
+    let client_ip = settings.trusted_proxies.client_ip(req.head());
 
-    let url_path = req.path().trim_start_matches('/');
+    let is_signed = || {
+        let path = format!("/{}", url_path);
+        settings.is_signed(&hostname, &path, req.query_string())
+    };
+    if let Err(denied) = check_access(&site.config, site_path, client_ip, req.headers(), is_signed)
+    {
+        settings.metrics.record_denied(&hostname);
+        return denied.into_response(&hostname);
+    }
+
+    if let Some(rate_limit) = site
+        .config
+        .rate_limit
+        .as_ref()
+        .or(settings.rate_limit.as_ref())
+    {
+        if let Some(ip) = client_ip {
+            let limited = settings.rate_limiter.check(ip, &site.get_key(), rate_limit);
+            if let Err(retry_after) = limited {
+                settings.metrics.record_rate_limited(&hostname);
+                return HttpResponse::TooManyRequests()
+                    .header(RETRY_AFTER, retry_after.as_secs_f64().ceil().to_string())
+                    .finish();
+            }
+        }
+    }
 
     if settings
         .deny_prefixes
         .iter()
-        .chain(site.config.deny_prefixes.iter())
         .any(|prefix| url_path.starts_with(prefix))
+        || site
+            .config
+            .deny_prefixes
+            .iter()
+            .any(|prefix| site_path.starts_with(prefix))
     {
+        settings.metrics.record_denied(&hostname);
         return HttpResponse::NotFound().finish();
     }
 
-    match site.get_file_for_path(&url_path).await {
-        Ok(p) => NamedFile::open(p)
-            .expect("Failed to open file")
-            .disable_content_disposition()
-            .into_response(&req)
-            .expect("Failed to turn file into response"),
+    if let Some(protection) = &site.config.hotlink_protection {
+        if !protection.is_allowed(site_path, &hostname, req.headers()) {
+            settings.metrics.record_denied(&hostname);
+            return match &protection.replacement {
+                Some(replacement) => {
+                    open_file(
+                        &req,
+                        &settings,
+                        &site,
+                        replacement.trim_start_matches('/'),
+                        &request_id,
+                    )
+                    .await
+                }
+                None => HttpResponse::Forbidden().finish(),
+            };
+        }
+    }
+
+    open_file(&req, &settings, &site, site_path, &request_id).await
+}
+
+async fn open_file(
+    req: &HttpRequest,
+    settings: &Settings,
+    site: &Site,
+    site_path: &str,
+    request_id: &RequestId,
+) -> HttpResponse {
+    match site
+        .get_file_or_well_known(site_path, settings.well_known_dir.as_deref())
+        .await
+    {
+        Ok(p) => {
+            let response = NamedFile::open(&p)
+                .map_err(Error::from)
+                .and_then(|file| file.disable_content_disposition().into_response(req));
+            match response {
+                Ok(r) => {
+                    req.extensions_mut().insert(ServedFile(p));
+                    r
+                }
+                Err(e) => {
+                    error!("[{}] Failed to serve {}: {}", request_id, p.display(), e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            }
+        }
         Err(_) => HttpResponse::NotFound().finish(),
     }
 }
@@ -48,8 +173,9 @@ mod tests {
     use super::*;
 
     use crate::app::configure_app;
+    use crate::rate_limit::RateLimit;
     use crate::site_config::CONFIG_FILENAME;
-    use crate::test_utils::get_test_settings;
+    use crate::test_utils::{get_test_settings, TestDir};
     use actix_web::http::{header, Method};
     use actix_web::web::Bytes;
     use actix_web::{test, App};
@@ -72,7 +198,50 @@ mod tests {
             .header(header::HOST, "localhost")
             .to_http_request();
 
-        assert_eq!(get_hostname(&request), "localhost");
+        assert_eq!(
+            get_hostname(&request, &TrustedProxies::default()),
+            "localhost"
+        );
+    }
+
+    #[test]
+    fn test_get_hostname_forwarded() {
+        let request = test::TestRequest::get()
+            .uri("/")
+            .header(header::HOST, "traefik-pages")
+            .header("X-Forwarded-Host", "example.com")
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .to_http_request();
+        assert_eq!(
+            get_hostname(&request, &TrustedProxies::default()),
+            "traefik-pages"
+        );
+        let trusted_proxies = TrustedProxies::parse(&[String::from("10.0.0.1")]).unwrap();
+        assert_eq!(get_hostname(&request, &trusted_proxies), "example.com");
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        for (path, normalized) in [
+            ("/", ""),
+            ("/index.html", "index.html"),
+            ("/staff/", "staff/"),
+            ("/./staff/index.html", "staff/index.html"),
+            ("//staff//index.html", "staff/index.html"),
+            ("/sub/../staff/index.html", "staff/index.html"),
+            ("/staff/.", "staff/"),
+            ("/staff/a/..", "staff/"),
+            ("/staff/..", ""),
+        ] {
+            assert_eq!(
+                normalize_path(path).as_deref(),
+                Some(normalized),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(normalize_path("/../etc/passwd"), None);
+        assert_eq!(normalize_path("/staff/../../index.html"), None);
     }
 
     #[test]
@@ -82,7 +251,10 @@ mod tests {
             .header(header::HOST, "localhost:5000")
             .to_http_request();
 
-        assert_eq!(get_hostname(&request), "localhost");
+        assert_eq!(
+            get_hostname(&request, &TrustedProxies::default()),
+            "localhost"
+        );
     }
 
     #[tokio::test]
@@ -107,6 +279,14 @@ mod tests {
             get_content_at_path("localhost", "/sub/index.html").await,
             Bytes::from_static(b"localhost subdir\n")
         );
+        assert_eq!(
+            get_content_at_path("localhost", "/nested").await,
+            Bytes::from_static(b"localhost nested\n")
+        );
+        assert_eq!(
+            get_content_at_path("localhost", "/nested/index.html").await,
+            Bytes::from_static(b"localhost nested\n")
+        );
         assert_eq!(
             get_content_at_path("site1.localhost", "/").await,
             Bytes::from_static(b"Site 1\n")
@@ -156,6 +336,19 @@ mod tests {
         assert_eq!(response.status(), 404);
     }
 
+    #[tokio::test]
+    async fn test_nested_config_file() {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let request = test::TestRequest::get()
+            .uri(&format!("/nested/{}", CONFIG_FILENAME))
+            .header(header::HOST, "localhost")
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 404);
+    }
+
     #[tokio::test]
     async fn test_invalid_hostname() {
         let mut app =
@@ -212,4 +405,197 @@ mod tests {
         let response = test::call_service(&mut app, request).await;
         assert_eq!(response.status(), 404);
     }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let mut settings = get_test_settings();
+        settings.rate_limit = Some(RateLimit {
+            rate: 0.01,
+            burst: 2,
+        });
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings))).await;
+        let get_request = |addr: &str, path: &str| {
+            test::TestRequest::get()
+                .uri(path)
+                .header(header::HOST, "localhost")
+                .peer_addr(addr.parse().unwrap())
+                .to_request()
+        };
+
+        for _ in 0..2 {
+            let response = test::call_service(&mut app, get_request("10.0.0.1:1234", "/")).await;
+            assert_eq!(response.status(), 200);
+        }
+        let response = test::call_service(&mut app, get_request("10.0.0.1:1234", "/")).await;
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "100");
+
+        // Nested sites, and other clients, are limited separately
+        let response = test::call_service(&mut app, get_request("10.0.0.1:1234", "/nested/")).await;
+        assert_eq!(response.status(), 200);
+        let response = test::call_service(&mut app, get_request("10.0.0.2:1234", "/")).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_across_releases() {
+        let root = crate::releases::tests::create_releases("rate-limit", &["1", "2"]);
+        let mut settings = root.settings();
+        settings.rate_limit = Some(RateLimit {
+            rate: 0.01,
+            burst: 1,
+        });
+        settings.registry.rescan().await.unwrap();
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings.clone())))
+                .await;
+        let get_request = || {
+            test::TestRequest::get()
+                .uri("/")
+                .header(header::HOST, "example.com")
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .to_request()
+        };
+
+        assert_eq!(
+            test::call_service(&mut app, get_request()).await.status(),
+            200
+        );
+        crate::releases::activate(&root.join("example.com"), "1").unwrap();
+        settings.registry.refresh_site("example.com").await;
+        assert_eq!(
+            test::call_service(&mut app, get_request()).await.status(),
+            429
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ip_rules() {
+        let root = TestDir::new("ip-rules");
+        root.write("example.com/index.html", "index");
+        root.write("example.com/staff/index.html", "staff");
+        root.write(
+            &format!("example.com/{}", CONFIG_FILENAME),
+            "deny_ips = ['192.0.2.0/24']\n[[paths]]\nprefix = 'staff/'\nallow_ips = ['10.8.0.0/16']",
+        );
+
+        let mut settings = root.settings();
+        settings.trusted_proxies = TrustedProxies::parse(&[String::from("10.0.0.1")]).unwrap();
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings))).await;
+
+        for (forwarded_for, path, status) in [
+            ("10.8.1.1", "/", 200),
+            ("10.8.1.1", "/staff/", 200),
+            ("198.51.100.1", "/", 200),
+            ("198.51.100.1", "/staff/", 403),
+            ("198.51.100.1", "/staff", 403),
+            ("198.51.100.1", "/./staff/index.html", 403),
+            ("198.51.100.1", "//staff/index.html", 403),
+            ("198.51.100.1", "/sub/../staff/index.html", 403),
+            ("198.51.100.1", "/../staff/index.html", 404),
+            ("192.0.2.1", "/", 403),
+        ] {
+            let request = test::TestRequest::get()
+                .uri(path)
+                .header(header::HOST, "example.com")
+                .header("X-Forwarded-For", forwarded_for)
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .to_request();
+            let response = test::call_service(&mut app, request).await;
+            assert_eq!(response.status(), status, "{} {}", forwarded_for, path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hotlink_protection() {
+        let root = TestDir::new("hotlink");
+        for site in ["example.com", "example.org"] {
+            root.write(&format!("{}/images/cat.jpg", site), "cat");
+            root.write(&format!("{}/images/blocked.png", site), "blocked");
+        }
+        root.write(
+            &format!("example.com/{}", CONFIG_FILENAME),
+            "[hotlink_protection]\npaths = ['**/*.jpg']\nallowed_hosts = ['partner.com']",
+        );
+        root.write(
+            &format!("example.org/{}", CONFIG_FILENAME),
+            "[hotlink_protection]\npaths = ['**/*.jpg']\nreplacement = '/images/blocked.png'",
+        );
+
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, root.settings())))
+                .await;
+        let get_request = |hostname: &str, path: &str, referer: &str| {
+            test::TestRequest::get()
+                .uri(path)
+                .header(header::HOST, hostname)
+                .header(header::REFERER, referer)
+                .to_request()
+        };
+
+        for (referer, status) in [
+            ("https://example.com/", 200),
+            ("https://partner.com/page.html", 200),
+            ("https://PARTNER.com/page.html", 200),
+            ("https://thief.com/", 403),
+        ] {
+            let response = test::call_service(
+                &mut app,
+                get_request("example.com", "/images/cat.jpg", referer),
+            )
+            .await;
+            assert_eq!(response.status(), status, "{}", referer);
+        }
+        for path in [
+            "/./images/cat.jpg",
+            "//images/cat.jpg",
+            "/docs/../images/cat.jpg",
+        ] {
+            let response = test::call_service(
+                &mut app,
+                get_request("example.com", path, "https://thief.com/"),
+            )
+            .await;
+            assert_eq!(response.status(), 403, "{}", path);
+        }
+
+        let request = get_request("example.org", "/images/cat.jpg", "https://thief.com/");
+        let body = test::read_response(&mut app, request).await;
+        assert_eq!(body, Bytes::from_static(b"blocked"));
+    }
+
+    #[tokio::test]
+    async fn test_well_known_dir() {
+        let root = TestDir::new("well-known-sites");
+        root.write("example.com/.well-known/security.txt", "own");
+        root.write("example.org/index.html", "");
+        let shared = TestDir::new("well-known-shared");
+        shared.write("security.txt", "shared");
+
+        let mut settings = root.settings();
+        settings.well_known_dir = Some(shared.to_path_buf());
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings))).await;
+        let get_request = |hostname: &str, path: &str| {
+            test::TestRequest::get()
+                .uri(path)
+                .header(header::HOST, hostname)
+                .to_request()
+        };
+
+        for (hostname, body) in [("example.com", "own"), ("example.org", "shared")] {
+            let response =
+                test::read_response(&mut app, get_request(hostname, "/.well-known/security.txt"))
+                    .await;
+            assert_eq!(response, Bytes::from(body));
+        }
+        let response = test::call_service(
+            &mut app,
+            get_request("example.org", "/.well-known/missing.txt"),
+        )
+        .await;
+        assert_eq!(response.status(), 404);
+    }
 }
@@ -0,0 +1,185 @@
+use crate::request_id::RequestId;
+use crate::routes::serve::normalize_path;
+use crate::settings::Settings;
+use crate::site::is_valid_hostname;
+use actix_web::{web, HttpResponse};
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_EXPIRES_IN: u64 = 3600;
+/// A year. Longer-lived URLs are better served by making the path public.
+const MAX_EXPIRES_IN: u64 = 365 * 24 * 3600;
+
+#[derive(Deserialize)]
+pub struct SignRequest {
+    /// As it appears in the URL, starting with `/`
+    path: String,
+    /// Seconds until the URL expires
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct SignedUrl {
+    url: String,
+    /// The path, with the signature's query string
+    path: String,
+    /// Seconds since the Unix epoch
+    expires: u64,
+}
+
+pub async fn sign_url(
+    settings: web::Data<Settings>,
+    hostname: web::Path<String>,
+    body: web::Json<SignRequest>,
+    request_id: RequestId,
+) -> HttpResponse {
+    let signer = match &settings.url_signer {
+        Some(s) => s,
+        None => return HttpResponse::Conflict().body("URL signing isn't configured"),
+    };
+    // Signatures are checked against the normalized path, so it's what gets signed
+    let url_path = match normalize_path(&body.path) {
+        Some(p) if body.path.starts_with('/') && !body.path.contains('?') => p,
+        _ => {
+            return HttpResponse::BadRequest()
+                .body("Path must start with / and have no query string")
+        }
+    };
+    let expires_in = body.expires_in.unwrap_or(DEFAULT_EXPIRES_IN);
+    if expires_in > MAX_EXPIRES_IN {
+        return HttpResponse::BadRequest().body(format!(
+            "expires_in can be at most {} seconds",
+            MAX_EXPIRES_IN
+        ));
+    }
+    if !is_valid_hostname(&hostname) {
+        return HttpResponse::NotFound().finish();
+    }
+    let url_path = url_path.as_str();
+    if settings
+        .site_from_hostname(&hostname, url_path)
+        .await
+        .is_none()
+    {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let expires = now + expires_in;
+    let signed_path = format!("/{}", url_path);
+    let path = format!(
+        "{}?{}",
+        signed_path,
+        signer.sign(&hostname, &signed_path, expires)
+    );
+    info!(
+        "[{}] Signed {}{} until {}",
+        request_id, hostname, signed_path, expires
+    );
+
+    HttpResponse::Ok().json(SignedUrl {
+        url: format!("https://{}{}", hostname, path),
+        path,
+        expires,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::configure_app;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::site_config::CONFIG_FILENAME;
+    use crate::test_utils::{auth_credentials, TestDir};
+    use actix_web::http::header;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_signed_urls() {
+        let root = TestDir::new("signed-urls");
+        root.write("example.com/reports/q3.txt", "Q3");
+        root.write(
+            &format!("example.com/{}", CONFIG_FILENAME),
+            "[[paths]]\nprefix = 'reports/'\nprivate = true",
+        );
+
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, root.settings())))
+                .await;
+        let get_file = |path: &str| {
+            test::TestRequest::get()
+                .uri(path)
+                .header(header::HOST, "example.com")
+                .to_request()
+        };
+        let sign = |hostname: &str, body: Value| {
+            test::TestRequest::post()
+                .uri(&format!(
+                    "/{}/sites/{}/signed-urls",
+                    INTERNAL_ROUTE_PREFIX, hostname
+                ))
+                .header(header::AUTHORIZATION, auth_credentials())
+                .set_json(&body)
+                .to_request()
+        };
+
+        for path in [
+            "/reports/q3.txt",
+            "/./reports/q3.txt",
+            "/docs/../reports/q3.txt",
+            "//reports/q3.txt",
+        ] {
+            let response = test::call_service(&mut app, get_file(path)).await;
+            assert_eq!(response.status(), 403, "{}", path);
+        }
+
+        let response = test::call_service(
+            &mut app,
+            sign("example.com", json!({"path": "/reports/q3.txt"})),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        let signed: Value = test::read_body_json(response).await;
+        let path = signed["path"].as_str().unwrap();
+        assert!(path.starts_with("/reports/q3.txt?expires="));
+        assert_eq!(signed["url"], format!("https://example.com{}", path));
+
+        let response = test::call_service(&mut app, get_file(path)).await;
+        assert_eq!(response.status(), 200);
+        let dotted = path.replace("/reports/", "/./reports/");
+        let response = test::call_service(&mut app, get_file(&dotted)).await;
+        assert_eq!(response.status(), 200);
+        let tampered = path.replace("q3", "q4");
+        let response = test::call_service(&mut app, get_file(&tampered)).await;
+        assert_eq!(response.status(), 403);
+
+        for expires_in in [u64::MAX, 365 * 24 * 3600 + 1] {
+            let body = json!({"path": "/reports/q3.txt", "expires_in": expires_in});
+            let response = test::call_service(&mut app, sign("example.com", body)).await;
+            assert_eq!(response.status(), 400);
+        }
+
+        for path in ["reports/q3.txt", "/../q3.txt"] {
+            let response =
+                test::call_service(&mut app, sign("example.com", json!({ "path": path }))).await;
+            assert_eq!(response.status(), 400, "{}", path);
+        }
+        let response = test::call_service(
+            &mut app,
+            sign("example.com", json!({"path": "/docs/../reports/q3.txt"})),
+        )
+        .await;
+        let signed: Value = test::read_body_json(response).await;
+        assert!(signed["path"]
+            .as_str()
+            .unwrap()
+            .starts_with("/reports/q3.txt?"));
+        let response =
+            test::call_service(&mut app, sign("unknown.com", json!({"path": "/"}))).await;
+        assert_eq!(response.status(), 404);
+    }
+}
@@ -1,17 +1,167 @@
+use crate::request_id::RequestId;
 use crate::settings::Settings;
-use crate::site::Site;
-use actix_web::{web, HttpResponse};
+use crate::site::{is_valid_hostname, Site, SiteStats};
+use crate::site_config::SiteConfig;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::error;
+use serde_derive::Serialize;
+use std::io;
 
-pub async fn sites_list(settings: web::Data<Settings>) -> HttpResponse {
+#[derive(Serialize)]
+struct SiteDetails<'a> {
+    hostname: String,
+    path_prefix: Option<&'a str>,
+    /// The config after defaults are applied
+    config: &'a SiteConfig,
+    valid: bool,
+    config_error: Option<&'a str>,
+    has_index: bool,
+    #[serde(flatten)]
+    stats: SiteStats,
+}
+
+async fn get_site_details(site: &Site) -> io::Result<SiteDetails<'_>> {
+    Ok(SiteDetails {
+        hostname: site.get_hostname(),
+        path_prefix: site.get_path_prefix(),
+        config: &site.config,
+        valid: site.is_valid(),
+        config_error: site.config_error.as_deref(),
+        has_index: site.get_file_for_path("").await.is_ok(),
+        stats: site.get_stats().await?,
+    })
+}
+
+fn accepts_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
+pub async fn sites_list(
+    req: HttpRequest,
+    settings: web::Data<Settings>,
+    request_id: RequestId,
+) -> HttpResponse {
     let sites = match settings.discover_sites().await {
         Ok(s) => s,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
+        Err(e) => {
+            error!("[{}] Failed to discover sites: {}", request_id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
     };
 
+    if accepts_json(&req) {
+        let mut details = Vec::with_capacity(sites.len());
+        for site in sites.iter() {
+            match get_site_details(site).await {
+                Ok(d) => details.push(d),
+                Err(e) => {
+                    error!(
+                        "[{}] Failed to read {}: {}",
+                        request_id,
+                        site.get_hostname(),
+                        e
+                    );
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+        }
+        return HttpResponse::Ok().json(details);
+    }
+
     let hostnames = sites
         .iter()
-        .map(Site::get_hostname)
+        .map(|s| s.get_hostname())
         .collect::<Vec<String>>();
 
     HttpResponse::Ok().body(hostnames.join("\n") + "\n")
 }
+
+/// Details of the site at the root of a hostname, read fresh from disk, even if it's invalid
+pub async fn site_detail(
+    settings: web::Data<Settings>,
+    hostname: web::Path<String>,
+    request_id: RequestId,
+) -> HttpResponse {
+    if !is_valid_hostname(&hostname) {
+        return HttpResponse::NotFound().finish();
+    }
+    let site = match Site::from_hostname(settings.registry.sites_root(), &hostname).await {
+        Some(s) => s,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    match get_site_details(&site).await {
+        Ok(details) => HttpResponse::Ok().json(details),
+        Err(e) => {
+            error!("[{}] Failed to read {}: {}", request_id, hostname, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::configure_app;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::test_utils::{auth_credentials, get_test_settings};
+    use actix_web::http::header;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    fn get_request(path: &str) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri(&format!("/{}/sites{}", INTERNAL_ROUTE_PREFIX, path))
+            .header(header::AUTHORIZATION, auth_credentials())
+    }
+
+    #[tokio::test]
+    async fn test_sites_list() {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+
+        let body = test::read_response(&mut app, get_request("").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(body.lines().count(), 5);
+        assert!(body.lines().any(|l| l == "site1.localhost"));
+
+        let request = get_request("")
+            .header(header::ACCEPT, "application/json")
+            .to_request();
+        let sites: Value = test::read_response_json(&mut app, request).await;
+        let sites = sites.as_array().unwrap();
+        assert_eq!(sites.len(), 5);
+        assert!(sites
+            .iter()
+            .any(|s| s["hostname"] == "localhost" && s["path_prefix"] == "nested"));
+    }
+
+    #[tokio::test]
+    async fn test_site_detail() {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+
+        let request = get_request("/no-middleware.localhost").to_request();
+        let site: Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(site["hostname"], "no-middleware.localhost");
+        assert_eq!(site["valid"], true);
+        assert_eq!(site["has_index"], true);
+        assert_eq!(site["files"], 2);
+        assert_eq!(site["config"]["default_middlewares"], false);
+        assert_eq!(site["config"]["dir_index_name"], "index.html");
+        assert_eq!(site["config"]["owner"], "Platform team");
+        assert_eq!(site["config"]["contact"], "platform@example.com");
+
+        let request = get_request("/no-index.localhost").to_request();
+        let site: Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(site["has_index"], false);
+
+        let request = get_request("/missing.localhost").to_request();
+        assert_eq!(test::call_service(&mut app, request).await.status(), 404);
+    }
+}
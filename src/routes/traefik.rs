@@ -1,109 +1,253 @@
-use crate::settings::{Settings, RETRY_COUNT};
-use crate::site::Site;
-use actix_web::{web, HttpResponse};
-use serde_json::{json, Value};
+use crate::format::Format;
+use crate::proxy::traefik::{build_provider_config, get_provider_config};
+use crate::request_id::RequestId;
+use crate::settings::Settings;
+use actix_web::http::header::{ETag, EntityTag, Header, IfNoneMatch};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::error;
+use serde_derive::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
 
-const DEFAULT_MIDDLEWARE_NAME: &str = "tp-default";
+struct CachedConfig {
+    generation: u64,
+    body: Bytes,
+    etag: EntityTag,
+}
 
-fn get_router_name(site: &Site) -> String {
-    site.get_hostname().replace('.', "-")
+/// Serialised provider configs, kept until the site registry changes
+#[derive(Default)]
+pub struct ProviderCache {
+    configs: Mutex<HashMap<Format, Arc<CachedConfig>>>,
 }
 
-/// Routers look funny, so no point defining as a struct
-fn serialize_router(site: &Site, settings: &Settings) -> Value {
-    let mut router = json!({
-        "rule": format!("Host(`{}`)", site.get_hostname()),
-        "service": &settings.traefik_service,
-        "middlewares": [DEFAULT_MIDDLEWARE_NAME]
-    });
-    if let Some(cert_resolver) = &settings.traefik_cert_resolver {
-        router.as_object_mut().unwrap().insert(
-            String::from("tls"),
-            json!({ "certResolver": cert_resolver }),
-        );
+impl ProviderCache {
+    async fn get(&self, settings: &Settings, format: Format) -> io::Result<Arc<CachedConfig>> {
+        let generation = settings.registry.generation();
+
+        if let Some(cached) = self.configs.lock().unwrap().get(&format) {
+            if cached.generation == generation {
+                return Ok(cached.clone());
+            }
+        }
+
+        let body = format.serialize(&get_provider_config(settings).await?)?;
+        let cached = Arc::new(CachedConfig {
+            generation,
+            etag: EntityTag::strong(format!("{:x}", Sha256::digest(body.as_bytes()))),
+            body: Bytes::from(body),
+        });
+        self.configs.lock().unwrap().insert(format, cached.clone());
+        Ok(cached)
     }
-    router
 }
 
-fn get_middleware() -> Value {
-    json!({
-        DEFAULT_MIDDLEWARE_NAME: {
-            "chain": {
-                "middlewares": [
-                    "tp-retry"
-                ]
-            }
-        },
-        "tp-retry": {
-            "retry": {
-                "attempts": RETRY_COUNT
-            }
+fn is_not_modified(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProviderQuery {
+    format: Option<String>,
+}
+
+pub async fn provider_diagnostics(
+    settings: web::Data<Settings>,
+    request_id: RequestId,
+) -> HttpResponse {
+    match build_provider_config(&settings).await {
+        Ok((_, diagnostics)) => HttpResponse::Ok().json(json!({ "diagnostics": diagnostics })),
+        Err(e) => {
+            error!("[{}] Failed to build provider config: {}", request_id, e);
+            HttpResponse::InternalServerError().finish()
         }
-    })
+    }
 }
 
-pub async fn traefik_provider(settings: web::Data<Settings>) -> HttpResponse {
-    let sites = match settings.discover_sites().await {
-        Ok(s) => s,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
+pub async fn traefik_provider(
+    req: HttpRequest,
+    settings: web::Data<Settings>,
+    cache: web::Data<ProviderCache>,
+    query: web::Query<ProviderQuery>,
+    request_id: RequestId,
+) -> HttpResponse {
+    let format = match query.format.as_deref() {
+        Some(name) => match Format::from_name(name) {
+            Some(f) => f,
+            None => return HttpResponse::BadRequest().finish(),
+        },
+        None => Format::Json,
     };
-    let routers: HashMap<String, Value> = sites
-        .iter()
-        .map(|s| (get_router_name(s), serialize_router(s, &settings)))
-        .collect();
-
-    HttpResponse::Ok().json(json!({
-        "http": {
-            "routers": routers,
-            "middlewares": get_middleware()
+    settings.metrics.record_provider_poll();
+
+    let cached = match cache.get(&settings, format).await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("[{}] Failed to build provider config: {}", request_id, e);
+            return HttpResponse::InternalServerError().finish();
         }
-    }))
+    };
+
+    if is_not_modified(&req, &cached.etag) {
+        return HttpResponse::NotModified()
+            .set(ETag(cached.etag.clone()))
+            .finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .set(ETag(cached.etag.clone()))
+        .body(cached.body.clone())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use crate::site::Site;
-    use crate::test_utils::{get_example_dir, get_test_settings};
-
-    #[test]
-    fn test_default_middleware() {
-        let middleware = get_middleware();
-        let default_middleware = &middleware[DEFAULT_MIDDLEWARE_NAME];
-        let chain_middlewares = default_middleware["chain"]["middlewares"]
-            .as_array()
-            .unwrap();
-        assert_eq!(chain_middlewares.len(), 1);
-        for m in chain_middlewares.iter() {
-            assert!(middleware.get(m.as_str().unwrap()).is_some());
-        }
+    use crate::app::configure_app;
+    use crate::routes::INTERNAL_ROUTE_PREFIX;
+    use crate::test_utils::{auth_credentials, get_test_settings, TestDir};
+    use actix_web::http::header;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    fn get_provider_request(query: &str) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri(&format!("/{}/provider{}", INTERNAL_ROUTE_PREFIX, query))
+            .header(header::AUTHORIZATION, auth_credentials())
+    }
+
+    async fn get_provider_response(query: &str) -> actix_web::dev::ServiceResponse {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        test::call_service(&mut app, get_provider_request(query).to_request()).await
     }
 
     #[tokio::test]
-    async fn test_router_name() {
-        let example_site = Site::new(get_example_dir().join("localhost")).await;
-        assert_eq!(get_router_name(&example_site), "localhost");
+    async fn test_diagnostics_endpoint() {
+        let response = get_provider_response("/diagnostics").await;
+        assert_eq!(response.status(), 200);
+        let body = test::read_body(response).await;
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({ "diagnostics": [] })
+        );
+    }
 
-        let example_site_2 = Site::new(get_example_dir().join("site1.localhost")).await;
-        assert_eq!(get_router_name(&example_site_2), "site1-localhost");
+    #[tokio::test]
+    async fn test_provider_diagnostics() {
+        let mut settings = get_test_settings();
+        settings.default_middlewares = vec![String::from("compress")];
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, settings))).await;
+        let config: Value =
+            test::read_response_json(&mut app, get_provider_request("").to_request()).await;
+        assert!(config.get("diagnostics").is_none());
+        assert!(config["http"]["routers"].get("localhost").is_some());
+
+        let request = get_provider_request("/diagnostics").to_request();
+        let response: Value = test::read_response_json(&mut app, request).await;
+        assert_eq!(
+            response["diagnostics"],
+            json!([{"name": "tp-default", "problem": "Unknown middleware compress"}])
+        );
     }
 
     #[tokio::test]
-    async fn test_serialize_router() {
-        let settings = get_test_settings();
-        let example_site = settings.site_from_hostname("localhost").await.unwrap();
+    async fn test_provider_formats() {
+        let expected = get_provider_config(&get_test_settings()).await.unwrap();
+
+        let response = get_provider_response("").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body = test::read_body(response).await;
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), expected);
+
+        let response = get_provider_response("?format=yaml").await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/yaml"
+        );
+        let body = test::read_body(response).await;
+        assert_eq!(serde_yaml::from_slice::<Value>(&body).unwrap(), expected);
+
+        let response = get_provider_response("?format=toml").await;
+        let body = test::read_body(response).await;
         assert_eq!(
-            serialize_router(&example_site, &settings),
-            json!({
-                "middlewares": [DEFAULT_MIDDLEWARE_NAME],
-                "rule": "Host(`localhost`)",
-                "service": "traefik-service@docker",
-                "tls": {
-                    "certResolver": "le"
-                }
-            })
+            toml::from_str::<Value>(std::str::from_utf8(&body).unwrap()).unwrap(),
+            expected
         );
     }
+
+    #[tokio::test]
+    async fn test_provider_unknown_format() {
+        let response = get_provider_response("?format=xml").await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_provider_etag() {
+        let mut app =
+            test::init_service(App::new().configure(|cfg| configure_app(cfg, get_test_settings())))
+                .await;
+        let response = test::call_service(&mut app, get_provider_request("").to_request()).await;
+        assert_eq!(response.status(), 200);
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+
+        let response = test::call_service(&mut app, get_provider_request("").to_request()).await;
+        assert_eq!(response.headers().get(header::ETAG).unwrap(), &etag);
+
+        let request = get_provider_request("")
+            .header(header::IF_NONE_MATCH, etag.clone())
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers().get(header::ETAG).unwrap(), &etag);
+
+        let request = get_provider_request("?format=yaml")
+            .header(header::IF_NONE_MATCH, etag)
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 200);
+
+        let request = get_provider_request("")
+            .header(header::IF_NONE_MATCH, "\"something-else\"")
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_provider_cache_invalidated() {
+        let root = TestDir::new("provider-cache");
+        root.write("example.com/index.html", "");
+
+        let settings = root.settings();
+        settings.discover_sites().await.unwrap();
+        let cache = ProviderCache::default();
+
+        let first = cache.get(&settings, Format::Json).await.unwrap();
+        let second = cache.get(&settings, Format::Json).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        std::fs::create_dir(root.join("example.org")).unwrap();
+        settings.registry.refresh_site("example.org").await;
+        let third = cache.get(&settings, Format::Json).await.unwrap();
+        assert_ne!(first.etag, third.etag);
+        assert!(std::str::from_utf8(&third.body)
+            .unwrap()
+            .contains("Host(`example.org`)"));
+    }
 }
@@ -1,24 +1,72 @@
+use crate::access_log::AccessLog;
+use crate::auth::ApiToken;
+use crate::client_ip::TrustedProxies;
+use crate::metrics::Metrics;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::registry::SiteRegistry;
+use crate::releases::SiteLocks;
 use crate::site::Site;
+use crate::throttle::FailureThrottle;
+use crate::url_signing::UrlSigner;
 use std::io;
 use std::path::PathBuf;
-
-pub const RETRY_COUNT: u8 = 4;
+use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Clone)]
 pub struct Settings {
-    pub sites_root: PathBuf,
     pub traefik_service: String,
+    pub traefik_service_urls: Vec<String>,
     pub traefik_cert_resolver: Option<String>,
-    pub auth_password: String,
+    pub api_tokens: Vec<ApiToken>,
+    pub trusted_proxies: TrustedProxies,
+    pub auth_throttle: Arc<FailureThrottle>,
     pub deny_prefixes: Vec<String>,
+    pub retry_attempts: u8,
+    pub retry_initial_interval: Option<String>,
+    pub default_middlewares: Vec<String>,
+    pub kubernetes_namespace: String,
+    pub kubernetes_service: String,
+    pub kubernetes_service_port: u16,
+    /// Where other reverse proxies should send traffic for `traefik-pages`
+    pub upstream_urls: Vec<String>,
+    /// Largest archive which can be uploaded, in bytes
+    pub deploy_max_size: usize,
+    /// Largest total size an uploaded archive can extract to, in bytes
+    pub deploy_max_extracted_size: u64,
+    /// Most files and directories an uploaded archive can contain
+    pub deploy_max_entries: usize,
+    /// How many releases of each site to keep after a deploy
+    pub release_retention: usize,
+    pub site_locks: Arc<SiteLocks>,
+    /// Most sites which may have invalid config before `traefik-pages` reports itself as not ready
+    pub ready_max_config_errors: usize,
+    /// Applied to every site which doesn't set its own
+    pub rate_limit: Option<RateLimit>,
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Without a key, private paths can't be served at all
+    pub url_signer: Option<UrlSigner>,
+    /// Served under `/.well-known/` for sites which don't have their own file
+    pub well_known_dir: Option<PathBuf>,
+    pub registry: Arc<SiteRegistry>,
+    pub metrics: Arc<Metrics>,
+    pub access_log: Arc<AccessLog>,
+    pub started: Instant,
 }
 
 impl Settings {
-    pub async fn discover_sites(&self) -> io::Result<Vec<Site>> {
-        Site::discover_all(&self.sites_root).await
+    pub async fn discover_sites(&self) -> io::Result<Vec<Arc<Site>>> {
+        self.registry.sites().await
+    }
+
+    /// Whether a request's query string signs its path, for private paths
+    pub fn is_signed(&self, hostname: &str, path: &str, query: &str) -> bool {
+        self.url_signer
+            .as_ref()
+            .is_some_and(|signer| signer.verify(hostname, path, query))
     }
 
-    pub async fn site_from_hostname(&self, hostname: &str) -> Option<Site> {
-        Site::from_hostname(&self.sites_root, hostname).await
+    pub async fn site_from_hostname(&self, hostname: &str, url_path: &str) -> Option<Arc<Site>> {
+        self.registry.get(hostname, url_path).await
     }
 }
@@ -1,39 +1,128 @@
 use crate::files::handle_index;
 use crate::files::{ensure_file, is_dir, safe_join};
+use crate::releases::{CURRENT_LINK, RELEASES_DIR};
 use crate::site_config::{SiteConfig, CONFIG_FILENAME};
+use log::warn;
+use serde_derive::Serialize;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 use tokio::fs;
 use url::Host;
 
+/// Where files shared by every site can be served from
+pub const WELL_KNOWN_PREFIX: &str = ".well-known/";
+
+/// How many directories deep nested sites are looked for, so whole sites aren't walked on every
+/// scan
+const MAX_NESTED_DEPTH: usize = 3;
+
 pub fn is_valid_hostname(hostname: &str) -> bool {
     !hostname.starts_with('.') && Host::parse(hostname).is_ok()
 }
 
+#[derive(Debug, PartialEq)]
 pub struct Site {
     root: PathBuf,
+    hostname: String,
+    path_prefix: Option<String>,
     pub config: SiteConfig,
     config_path: Option<PathBuf>,
+    pub config_error: Option<String>,
+    /// Nested configs which couldn't be read, so are served as part of this site
+    pub nested_config_errors: Vec<NestedConfigError>,
+}
+
+/// A `pages.toml` within a site which couldn't be read, so can't say whether it's a nested site
+#[derive(Debug, PartialEq)]
+pub struct NestedConfigError {
+    pub path_prefix: String,
+    pub config_path: PathBuf,
+    pub error: String,
+}
+
+/// Totals for the files in a site, including any sites nested within it
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct SiteStats {
+    pub files: u64,
+    /// Total size of the files, in bytes
+    pub size: u64,
+    /// When a file was last modified, in seconds since the Unix epoch
+    pub last_modified: Option<u64>,
+}
+
+/// Sites using releases are served from their active release.
+///
+/// If there's a `releases` directory but `current` doesn't point at one of them, there's nothing
+/// safe to serve, as the site's directory holds every release.
+async fn get_content_root(site_dir: &Path) -> Result<PathBuf, String> {
+    let releases_dir = site_dir.join(RELEASES_DIR);
+    if !is_dir(&releases_dir).await {
+        return Ok(site_dir.to_path_buf());
+    }
+    match safe_join(&releases_dir, site_dir.join(CURRENT_LINK)).await {
+        Ok(release) if release != releases_dir && is_dir(&release).await => Ok(release),
+        _ => Err(format!(
+            "{} doesn't point at a release in {}",
+            CURRENT_LINK, RELEASES_DIR
+        )),
+    }
 }
 
 impl Site {
     pub async fn new(root: PathBuf) -> Self {
+        let hostname = root.file_name().unwrap().to_str().unwrap().to_owned();
+        match get_content_root(&root).await {
+            Ok(content_root) => Site::load(content_root, hostname, None).await,
+            Err(error) => {
+                warn!("Invalid site in {}: {}", root.display(), error);
+                Site {
+                    root,
+                    hostname,
+                    path_prefix: None,
+                    config: SiteConfig::default(),
+                    config_path: None,
+                    config_error: Some(error),
+                    nested_config_errors: vec![],
+                }
+            }
+        }
+    }
+
+    async fn load(root: PathBuf, hostname: String, path_prefix: Option<String>) -> Self {
         debug_assert!(root.is_dir());
 
         let maybe_config_path = safe_join(&root, CONFIG_FILENAME).await.ok();
 
-        let config = match maybe_config_path {
+        let maybe_config = match maybe_config_path {
             Some(ref p) => SiteConfig::new(p).await,
-            None => SiteConfig::default(),
+            None => Ok(SiteConfig::default()),
+        };
+
+        let (config, config_error) = match maybe_config {
+            Ok(c) => (c, None),
+            Err(e) => {
+                warn!("Invalid site config in {}: {}", root.display(), e);
+                (SiteConfig::default(), Some(e.to_string()))
+            }
         };
 
         Site {
             root,
+            hostname,
+            path_prefix,
             config,
             config_path: maybe_config_path,
+            config_error,
+            nested_config_errors: vec![],
         }
     }
 
+    /// Sites with an invalid config aren't served, rather than being served without their restrictions
+    pub fn is_valid(&self) -> bool {
+        self.config_error.is_none()
+    }
+
     pub fn get_index_file(&self) -> Option<String> {
         if self.config.dir_index {
             Some(self.config.dir_index_name.clone())
@@ -42,8 +131,41 @@ impl Site {
         }
     }
 
+    /// Directory the site's files are served from
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn get_config_path(&self) -> Option<&Path> {
+        self.config_path.as_deref()
+    }
+
     pub fn get_hostname(&self) -> String {
-        self.root.file_name().unwrap().to_str().unwrap().to_owned()
+        self.hostname.clone()
+    }
+
+    /// URL path (without leading or trailing slashes) a nested site is served under
+    pub fn get_path_prefix(&self) -> Option<&str> {
+        self.path_prefix.as_deref()
+    }
+
+    /// Identifies the site across deploys and rollbacks, unlike its root, which moves with each release
+    pub fn get_key(&self) -> String {
+        match self.path_prefix {
+            None => self.hostname.clone(),
+            Some(ref prefix) => format!("{}/{}", self.hostname, prefix),
+        }
+    }
+
+    /// Get the path relative to this site, if this site serves the given URL path
+    pub fn strip_path_prefix<'a>(&self, url_path: &'a str) -> Option<&'a str> {
+        match self.path_prefix {
+            None => Some(url_path),
+            Some(ref prefix) => match url_path.strip_prefix(prefix.as_str())? {
+                "" => Some(""),
+                rest => rest.strip_prefix('/'),
+            },
+        }
     }
 
     pub async fn from_hostname(sites_root: impl AsRef<Path>, hostname: &str) -> Option<Site> {
@@ -52,6 +174,104 @@ impl Site {
         Some(Site::new(site_root).await)
     }
 
+    /// Find subdirectories marked as sites in their own right, up to `MAX_NESTED_DEPTH` deep.
+    /// Hidden directories, such as `.git`, are skipped.
+    ///
+    /// Configs which can't be read are recorded against this site, rather than becoming sites.
+    async fn discover_nested(&mut self) -> io::Result<Vec<Site>> {
+        // Its directory may not be what would have been served, such as a broken release layout
+        if !self.is_valid() {
+            return Ok(vec![]);
+        }
+        let mut nested = vec![];
+        let mut config_errors = vec![];
+        let mut dirs = vec![(self.root.clone(), 0)];
+
+        while let Some((dir, depth)) = dirs.pop() {
+            if depth == MAX_NESTED_DEPTH {
+                continue;
+            }
+            let mut entries = fs::read_dir(&dir).await?;
+
+            while let Some(entry) = entries.next_entry().await? {
+                // Symlinks aren't followed, to avoid walking in circles
+                if !entry.file_type().await?.is_dir()
+                    || entry.file_name().to_string_lossy().starts_with('.')
+                {
+                    continue;
+                }
+                let path = entry.path();
+
+                if fs::metadata(path.join(CONFIG_FILENAME)).await.is_ok() {
+                    let prefix = path
+                        .strip_prefix(&self.root)
+                        .unwrap()
+                        .components()
+                        .filter_map(|c| c.as_os_str().to_str())
+                        .collect::<Vec<&str>>()
+                        .join("/");
+                    let site =
+                        Site::load(path.clone(), self.get_hostname(), Some(prefix.clone())).await;
+
+                    match site.config_error {
+                        Some(error) => config_errors.push(NestedConfigError {
+                            path_prefix: prefix,
+                            config_path: path.join(CONFIG_FILENAME),
+                            error,
+                        }),
+                        None if site.config.site => nested.push(site),
+                        None => {}
+                    }
+                }
+                dirs.push((path, depth + 1));
+            }
+        }
+
+        self.nested_config_errors = config_errors;
+        Ok(nested)
+    }
+
+    pub async fn get_stats(&self) -> io::Result<SiteStats> {
+        let mut stats = SiteStats::default();
+        let mut dirs = vec![self.root.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = fs::symlink_metadata(entry.path()).await?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                } else if metadata.is_file() {
+                    stats.files += 1;
+                    stats.size += metadata.len();
+                    let modified = metadata
+                        .modified()?
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    stats.last_modified = stats.last_modified.max(Some(modified));
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Load the site for a hostname, along with any sites nested within it
+    pub async fn discover_for_hostname(
+        sites_root: impl AsRef<Path>,
+        hostname: &str,
+    ) -> io::Result<Vec<Site>> {
+        let mut site = match Site::from_hostname(sites_root, hostname).await {
+            Some(s) => s,
+            None => return Ok(vec![]),
+        };
+        let mut sites = site.discover_nested().await?;
+        sites.push(site);
+        Ok(sites)
+    }
+
     pub async fn discover_all(sites_root: impl AsRef<Path>) -> io::Result<Vec<Site>> {
         let mut sites = vec![];
 
@@ -59,9 +279,10 @@ impl Site {
 
         while let Some(entry) = entries.next_entry().await? {
             if is_dir(entry.path()).await {
-                let site = Site::new(entry.path()).await;
+                let mut site = Site::new(entry.path()).await;
 
                 if is_valid_hostname(&site.get_hostname()) {
+                    sites.extend(site.discover_nested().await?);
                     sites.push(site);
                 }
             }
@@ -91,18 +312,39 @@ impl Site {
         }
         maybe_joined_path
     }
+
+    /// Like `get_file_for_path`, but falling back to `well_known_dir` for files under
+    /// `/.well-known/` which the site doesn't have itself
+    pub async fn get_file_or_well_known(
+        &self,
+        path: &str,
+        well_known_dir: Option<&Path>,
+    ) -> io::Result<PathBuf> {
+        let result = self.get_file_for_path(path).await;
+        if result.is_ok() || self.path_prefix.is_some() {
+            return result;
+        }
+        match (well_known_dir, path.strip_prefix(WELL_KNOWN_PREFIX)) {
+            (Some(dir), Some(well_known_path)) => {
+                ensure_file(safe_join(dir, well_known_path).await).await
+            }
+            _ => result,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use crate::test_utils::get_example_dir;
+    use crate::releases::activate;
+    use crate::releases::tests::create_releases;
+    use crate::test_utils::{get_example_dir, TestDir};
 
     #[tokio::test]
     async fn test_discover_all() {
         let sites = Site::discover_all(get_example_dir()).await.unwrap();
-        assert_eq!(sites.len(), 3);
+        assert_eq!(sites.len(), 5);
         let site_hostnames = sites
             .iter()
             .map(Site::get_hostname)
@@ -110,6 +352,127 @@ mod tests {
         assert!(site_hostnames.contains(&String::from("localhost")));
         assert!(site_hostnames.contains(&String::from("site1.localhost")));
         assert!(site_hostnames.contains(&String::from("no-index.localhost")));
+        assert!(site_hostnames.contains(&String::from("no-middleware.localhost")));
+    }
+
+    #[tokio::test]
+    async fn test_discover_nested() {
+        let sites = Site::discover_for_hostname(get_example_dir(), "localhost")
+            .await
+            .unwrap();
+        assert_eq!(sites.len(), 2);
+
+        let nested = sites
+            .iter()
+            .find(|s| s.get_path_prefix().is_some())
+            .unwrap();
+        assert_eq!(nested.get_hostname(), "localhost");
+        assert_eq!(nested.get_path_prefix(), Some("nested"));
+        assert_eq!(
+            nested.get_file_for_path("").await.unwrap(),
+            get_example_dir().join("localhost/nested/index.html")
+        );
+
+        assert!(
+            Site::discover_for_hostname(get_example_dir(), "missing.localhost")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discover_nested_invalid_config() {
+        let root = TestDir::new("nested-invalid");
+        root.write("example.com/docs/index.html", "docs");
+        root.write(&format!("example.com/docs/{}", CONFIG_FILENAME), "site = ");
+        root.write(
+            &format!("example.com/other/{}", CONFIG_FILENAME),
+            "dir_index = false",
+        );
+
+        let sites = Site::discover_for_hostname(&root, "example.com")
+            .await
+            .unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(
+            sites[0].get_file_for_path("docs/").await.unwrap(),
+            root.join("example.com/docs/index.html")
+        );
+        assert_eq!(sites[0].nested_config_errors.len(), 1);
+        assert_eq!(sites[0].nested_config_errors[0].path_prefix, "docs");
+        assert_eq!(
+            sites[0].nested_config_errors[0].config_path,
+            root.join("example.com/docs").join(CONFIG_FILENAME)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_broken_release_layout() {
+        let root = TestDir::new("broken-release");
+        root.write("example.com/releases/1/index.html", "1");
+        root.write("example.com/releases/1/docs/pages.toml", "site = true");
+
+        // No `current`
+        let site = Site::new(root.join("example.com")).await;
+        assert!(!site.is_valid());
+        assert!(Site::discover_for_hostname(&root, "example.com")
+            .await
+            .unwrap()
+            .iter()
+            .all(|s| !s.is_valid()));
+
+        // `current` pointing at a missing release, or out of `releases`
+        for target in ["releases/2", "releases", "releases/1/docs/../../.."] {
+            let _ = std::fs::remove_file(root.join("example.com/current"));
+            std::os::unix::fs::symlink(target, root.join("example.com/current")).unwrap();
+            assert!(!Site::new(root.join("example.com")).await.is_valid());
+        }
+
+        let _ = std::fs::remove_file(root.join("example.com/current"));
+        std::os::unix::fs::symlink("releases/1", root.join("example.com/current")).unwrap();
+        let site = Site::new(root.join("example.com")).await;
+        assert!(site.is_valid());
+        assert_eq!(site.get_root(), root.join("example.com/releases/1"));
+    }
+
+    #[tokio::test]
+    async fn test_discover_nested_depth() {
+        let root = TestDir::new("nested-depth");
+        root.write("example.com/index.html", "");
+        for dir in ["a/b/c", "a/b/c/d", "a/.hidden"] {
+            root.write(
+                &format!("example.com/{}/{}", dir, CONFIG_FILENAME),
+                "site = true",
+            );
+        }
+        let prefixes: Vec<String> = Site::discover_for_hostname(&root, "example.com")
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|s| s.get_path_prefix().map(String::from))
+            .collect();
+        assert_eq!(prefixes, vec![String::from("a/b/c")]);
+    }
+
+    #[tokio::test]
+    async fn test_strip_path_prefix() {
+        let site = Site::new(get_example_dir().join("localhost")).await;
+        assert_eq!(site.strip_path_prefix("nested/page"), Some("nested/page"));
+
+        let nested = Site::load(
+            get_example_dir().join("localhost/nested"),
+            String::from("localhost"),
+            Some(String::from("nested")),
+        )
+        .await;
+        assert_eq!(nested.strip_path_prefix("nested"), Some(""));
+        assert_eq!(nested.strip_path_prefix("nested/"), Some(""));
+        assert_eq!(nested.strip_path_prefix("nested/page"), Some("page"));
+        assert_eq!(nested.strip_path_prefix("nested-page"), None);
+        assert_eq!(nested.strip_path_prefix("sub"), None);
+        assert_eq!(site.get_key(), "localhost");
+        assert_eq!(nested.get_key(), "localhost/nested");
     }
 
     #[tokio::test]
@@ -137,6 +500,99 @@ mod tests {
         assert!(site.get_file_for_path(CONFIG_FILENAME).await.is_err());
     }
 
+    #[tokio::test]
+    async fn test_get_file_or_well_known() {
+        let well_known_dir = TestDir::new("well-known");
+        well_known_dir.write("security.txt", "Contact: me");
+        well_known_dir.write("sub/index.html", "");
+        let site = Site::new(get_example_dir().join("localhost")).await;
+
+        assert_eq!(
+            site.get_file_or_well_known(".well-known/security.txt", Some(&well_known_dir))
+                .await
+                .unwrap(),
+            well_known_dir.join("security.txt")
+        );
+        assert_eq!(
+            site.get_file_or_well_known("index.html", Some(&well_known_dir))
+                .await
+                .unwrap(),
+            get_example_dir().join("localhost/index.html")
+        );
+        for path in [
+            "security.txt",
+            ".well-known/sub",
+            ".well-known/../index.html",
+            ".well-known/missing.txt",
+        ] {
+            assert!(site
+                .get_file_or_well_known(path, Some(&well_known_dir))
+                .await
+                .is_err());
+        }
+        assert!(site
+            .get_file_or_well_known(".well-known/security.txt", None)
+            .await
+            .is_err());
+
+        // Only sites at the root of their hostname have a `/.well-known/`
+        let nested = Site::discover_for_hostname(get_example_dir(), "localhost")
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|s| s.get_path_prefix().is_some())
+            .unwrap();
+        assert!(nested
+            .get_file_or_well_known(".well-known/security.txt", Some(&well_known_dir))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serves_current_release() {
+        let root = create_releases("site", &["1", "2"]);
+        let site_dir = root.join("example.com");
+        let site = Site::new(site_dir.clone()).await;
+        assert_eq!(site.get_hostname(), "example.com");
+        assert_eq!(
+            site.get_file_for_path("").await.unwrap(),
+            site_dir.join("releases/2/index.html")
+        );
+
+        activate(&site_dir, "1").unwrap();
+        let site = Site::new(site_dir.clone()).await;
+        assert_eq!(
+            site.get_file_for_path("index.html").await.unwrap(),
+            site_dir.join("releases/1/index.html")
+        );
+        assert!(site.get_file_for_path("../2/index.html").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_stats() {
+        let site = Site::new(get_example_dir().join("no-middleware.localhost")).await;
+        let stats = site.get_stats().await.unwrap();
+        assert_eq!(stats.files, 2);
+        assert_eq!(
+            stats.size,
+            std::fs::metadata(get_example_dir().join("no-middleware.localhost/index.html"))
+                .unwrap()
+                .len()
+                + std::fs::metadata(
+                    get_example_dir()
+                        .join("no-middleware.localhost")
+                        .join(CONFIG_FILENAME)
+                )
+                .unwrap()
+                .len()
+        );
+        assert!(stats.last_modified.is_some());
+
+        let empty = Site::new(get_example_dir().join("localhost/sub-no-index")).await;
+        let stats = empty.get_stats().await.unwrap();
+        assert_eq!((stats.files, stats.size), (1, 0));
+    }
+
     #[tokio::test]
     async fn test_from_path() {
         let site = Site::new(get_example_dir().join("localhost")).await;
@@ -1,4 +1,9 @@
-use serde_derive::Deserialize;
+use crate::hotlink::HotlinkProtection;
+use crate::ip_rules::IpRange;
+use crate::rate_limit::RateLimit;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
 use std::path::Path;
 use tokio::fs;
 use toml::from_str;
@@ -17,7 +22,46 @@ fn default_deny_prefixes() -> Vec<String> {
     vec![]
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct Credential {
+    pub username: String,
+    /// Hex encoded, so the password itself isn't stored
+    #[serde(skip_serializing)]
+    pub password_sha256: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct PathConfig {
+    /// Relative to the site, like `deny_prefixes`
+    pub prefix: String,
+
+    #[serde(default)]
+    pub allow_ips: Vec<IpRange>,
+
+    #[serde(default)]
+    pub deny_ips: Vec<IpRange>,
+
+    #[serde(default)]
+    pub credentials: Vec<Credential>,
+
+    #[serde(default)]
+    pub required_headers: BTreeMap<String, String>,
+
+    /// Only serve these paths through signed URLs
+    #[serde(default)]
+    pub private: bool,
+}
+
+impl PathConfig {
+    /// `site_path` must already be normalized. A directory prefix also matches the directory
+    /// without its trailing slash, as that serves the same index file.
+    pub fn matches(&self, site_path: &str) -> bool {
+        let prefix = self.prefix.trim_start_matches('/');
+        site_path.starts_with(prefix) || prefix.strip_suffix('/') == Some(site_path)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct SiteConfig {
     #[serde(default = "default_true")]
     pub dir_index: bool,
@@ -27,15 +71,102 @@ pub struct SiteConfig {
 
     #[serde(default = "default_deny_prefixes")]
     pub deny_prefixes: Vec<String>,
+
+    #[serde(default = "default_true")]
+    pub default_middlewares: bool,
+
+    /// Serve this subdirectory as a site in its own right
+    #[serde(default)]
+    pub site: bool,
+
+    /// Traefik service to route to, instead of the globally configured one
+    pub service: Option<String>,
+
+    /// Only serve the site to these addresses, unless empty
+    #[serde(default)]
+    pub allow_ips: Vec<IpRange>,
+
+    /// Never serve the site to these addresses
+    #[serde(default)]
+    pub deny_ips: Vec<IpRange>,
+
+    /// Basic auth credentials, any of which may be used to see the site
+    #[serde(default)]
+    pub credentials: Vec<Credential>,
+
+    /// Headers which requests must have, with these exact values
+    #[serde(default)]
+    pub required_headers: BTreeMap<String, String>,
+
+    /// Rules for parts of the site, on top of the site's own
+    #[serde(default)]
+    pub paths: Vec<PathConfig>,
+
+    /// Replaces the global rate limit for this site
+    pub rate_limit: Option<RateLimit>,
+
+    /// Only serve some paths when linked to from the site itself, or other allowed hosts
+    pub hotlink_protection: Option<HotlinkProtection>,
+
+    /// Who's responsible for the site, for reference only
+    pub owner: Option<String>,
+
+    /// How to reach the site's owner, for reference only
+    pub contact: Option<String>,
+}
+
+fn validate_credentials(credentials: &[Credential]) -> Result<(), String> {
+    for credential in credentials {
+        if credential.password_sha256.len() != 64
+            || !credential
+                .password_sha256
+                .chars()
+                .all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(format!(
+                "Password for {} must be a hex encoded SHA-256 digest",
+                credential.username
+            ));
+        }
+    }
+    Ok(())
 }
 
 impl SiteConfig {
-    pub async fn new(file: impl AsRef<Path>) -> Self {
-        let contents = fs::read_to_string(file)
-            .await
-            .expect("Failed to read site config");
+    pub async fn new(file: impl AsRef<Path>) -> io::Result<Self> {
+        SiteConfig::parse(&fs::read_to_string(file).await?)
+    }
+
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let config: SiteConfig =
+            from_str(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        config
+            .validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(config)
+    }
 
-        from_str(&contents).expect("Failed to parse site config")
+    /// Problems serde can't catch
+    fn validate(&self) -> Result<(), String> {
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.validate()?;
+        }
+        validate_credentials(&self.credentials)?;
+        for path in self.paths.iter() {
+            validate_credentials(&path.credentials)?;
+        }
+        Ok(())
+    }
+
+    /// Keys which aren't part of the config, and so are ignored, most likely by mistake
+    pub fn find_unknown_keys(contents: &str) -> io::Result<Vec<String>> {
+        let mut unknown = vec![];
+        let deserializer = &mut toml::Deserializer::new(contents);
+        serde_ignored::deserialize::<_, _, SiteConfig>(deserializer, |path| {
+            unknown.push(path.to_string())
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(unknown)
     }
 }
 
@@ -53,7 +184,31 @@ mod tests {
 
     #[tokio::test]
     async fn test_parse() {
-        SiteConfig::new(get_example_dir().join("localhost").join(CONFIG_FILENAME)).await;
+        SiteConfig::new(get_example_dir().join("localhost").join(CONFIG_FILENAME))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_parse_invalid() {
+        let path = std::env::temp_dir().join("traefik-pages-test-invalid-pages.toml");
+        fs::write(&path, "dir_index = 'yes'").await.unwrap();
+        let error = SiteConfig::new(&path).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[test]
+    fn test_find_unknown_keys() {
+        assert!(SiteConfig::find_unknown_keys("dir_index = false")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            SiteConfig::find_unknown_keys("dir_idnex = false\nowner = 'me'\ncolour = 'red'")
+                .unwrap(),
+            vec![String::from("dir_idnex"), String::from("colour")]
+        );
+        assert!(SiteConfig::find_unknown_keys("dir_index = 1").is_err());
     }
 
     #[test]
@@ -62,5 +217,53 @@ mod tests {
         assert!(site_config.dir_index);
         assert_eq!(&site_config.dir_index_name, "index.html");
         assert_eq!(site_config.deny_prefixes, Vec::<String>::new());
+        assert!(site_config.default_middlewares);
+        assert!(!site_config.site);
+        assert!(site_config.service.is_none());
+        assert!(site_config.owner.is_none());
+        assert!(site_config.contact.is_none());
+        assert!(site_config.rate_limit.is_none());
+        assert!(site_config.allow_ips.is_empty());
+        assert!(site_config.paths.is_empty());
+        assert!(site_config.hotlink_protection.is_none());
+    }
+
+    #[test]
+    fn test_path_matches() {
+        let site_config = SiteConfig::parse("[[paths]]\nprefix = '/staff/'").unwrap();
+        let path = &site_config.paths[0];
+        assert!(path.matches("staff/"));
+        assert!(path.matches("staff/index.html"));
+        assert!(path.matches("staff"));
+        assert!(!path.matches("staffing.html"));
+        assert!(!path.matches("index.html"));
+    }
+
+    #[test]
+    fn test_parse_access_rules() {
+        assert!(SiteConfig::parse("allow_ips = ['office']").is_err());
+        assert!(
+            SiteConfig::parse("[[credentials]]\nusername = 'me'\npassword_sha256 = 'abc'").is_err()
+        );
+        let site_config = SiteConfig::parse(&format!(
+            "[[paths]]\nprefix = 'staff/'\n[[paths.credentials]]\nusername = 'me'\npassword_sha256 = '{}'",
+            "A".repeat(64)
+        ))
+        .unwrap();
+        assert_eq!(site_config.paths[0].credentials[0].username, "me");
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let site_config = SiteConfig::parse("[rate_limit]\nrate = 5\nburst = 10").unwrap();
+        assert_eq!(
+            site_config.rate_limit,
+            Some(RateLimit {
+                rate: 5.0,
+                burst: 10
+            })
+        );
+        let error = SiteConfig::parse("rate_limit = { rate = 0, burst = 10 }").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
     }
 }
@@ -1,7 +1,19 @@
+use crate::access_log::{AccessLog, AccessLogFilter, AccessLogFormat};
+use crate::auth::{ApiToken, TokenScope};
+use crate::client_ip::TrustedProxies;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+use crate::registry::SiteRegistry;
+use crate::releases::SiteLocks;
 use crate::settings::Settings;
+use crate::throttle::FailureThrottle;
+use crate::url_signing::UrlSigner;
 use actix_web_httpauth::headers::authorization::Basic;
 use std::env::current_dir;
-use std::path::PathBuf;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub const TEST_PASSWORD: &str = "password";
 
@@ -11,11 +23,45 @@ pub fn get_example_dir() -> PathBuf {
 
 pub fn get_test_settings() -> Settings {
     Settings {
-        sites_root: get_example_dir(),
         traefik_service: String::from("traefik-service@docker"),
+        traefik_service_urls: Vec::new(),
         traefik_cert_resolver: Some(String::from("le")),
-        auth_password: TEST_PASSWORD.into(),
+        api_tokens: vec![
+            ApiToken::new("default", TEST_PASSWORD, vec![TokenScope::Admin]),
+            ApiToken::new(
+                "provider",
+                "provider-secret",
+                vec![TokenScope::ProviderRead],
+            ),
+        ],
+        trusted_proxies: TrustedProxies::default(),
+        auth_throttle: Arc::new(FailureThrottle::new(3, Duration::from_secs(60))),
         deny_prefixes: Vec::new(),
+        retry_attempts: 4,
+        retry_initial_interval: None,
+        default_middlewares: Vec::new(),
+        kubernetes_namespace: String::from("default"),
+        kubernetes_service: String::from("traefik-pages"),
+        kubernetes_service_port: 5000,
+        upstream_urls: vec![String::from("http://127.0.0.1:5000")],
+        deploy_max_size: 1024 * 1024,
+        deploy_max_extracted_size: 1024 * 1024,
+        deploy_max_entries: 1000,
+        release_retention: 3,
+        site_locks: Arc::new(SiteLocks::default()),
+        ready_max_config_errors: 0,
+        rate_limit: None,
+        rate_limiter: Arc::new(RateLimiter::default()),
+        url_signer: Some(UrlSigner::new(b"signing-key")),
+        well_known_dir: None,
+        registry: Arc::new(SiteRegistry::new(get_example_dir())),
+        metrics: Arc::new(Metrics::default()),
+        access_log: Arc::new(AccessLog::new(
+            AccessLogFormat::Text,
+            AccessLogFilter::default(),
+            None,
+        )),
+        started: Instant::now(),
     }
 }
 
@@ -25,6 +71,53 @@ pub fn auth_credentials() -> Basic {
     Basic::new(TEST_PASSWORD, password)
 }
 
+/// A scratch directory for a test, removed when dropped, even if the test panics
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Names must be unique, as tests run in parallel
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("traefik-pages-test-{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir(path.canonicalize().unwrap())
+    }
+
+    /// Write a file, creating the directories it's in
+    pub fn write(&self, path: &str, contents: &str) {
+        let path = self.0.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    /// Test settings, serving sites from this directory
+    pub fn settings(&self) -> Settings {
+        let mut settings = get_test_settings();
+        settings.registry = Arc::new(SiteRegistry::new(self.0.clone()));
+        settings
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -42,4 +135,15 @@ mod tests {
         assert_eq!(credentials.user_id(), TEST_PASSWORD);
         assert!(credentials.password().is_none());
     }
+
+    #[test]
+    fn test_test_dir() {
+        let path = {
+            let dir = TestDir::new("test-dir");
+            dir.write("example.com/index.html", "hello");
+            assert!(dir.join("example.com/index.html").is_file());
+            dir.to_path_buf()
+        };
+        assert!(!path.exists());
+    }
 }
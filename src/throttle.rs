@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// Locks out addresses which fail too many times within a window
+pub struct FailureThrottle {
+    max_failures: u32,
+    window: Duration,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl FailureThrottle {
+    pub fn new(max_failures: u32, window: Duration) -> Self {
+        FailureThrottle {
+            max_failures,
+            window,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long until `ip` may try again, if it's currently locked out
+    pub fn retry_after(&self, ip: IpAddr) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let entry = failures.get(&ip)?;
+        let elapsed = entry.since.elapsed();
+        if entry.count >= self.max_failures && elapsed < self.window {
+            Some(self.window - elapsed)
+        } else {
+            None
+        }
+    }
+
+    pub fn record_failure(&self, ip: IpAddr) {
+        let mut failures = self.failures.lock().unwrap();
+        // Forget expired windows, so the map doesn't grow forever
+        failures.retain(|_, f| f.since.elapsed() < self.window);
+        failures
+            .entry(ip)
+            .or_insert_with(|| Failures {
+                count: 0,
+                since: Instant::now(),
+            })
+            .count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread::sleep;
+
+    #[test]
+    fn test_locks_out_after_max_failures() {
+        let throttle = FailureThrottle::new(2, Duration::from_secs(60));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+        throttle.record_failure(ip);
+        assert!(throttle.retry_after(ip).is_none());
+        throttle.record_failure(ip);
+        let retry_after = throttle.retry_after(ip).unwrap();
+        assert!(retry_after <= Duration::from_secs(60));
+        assert!(retry_after > Duration::from_secs(59));
+
+        assert!(throttle.retry_after(other_ip).is_none());
+    }
+
+    #[test]
+    fn test_window_expires() {
+        let throttle = FailureThrottle::new(1, Duration::from_millis(50));
+        let ip: IpAddr = "::1".parse().unwrap();
+        throttle.record_failure(ip);
+        assert!(throttle.retry_after(ip).is_some());
+
+        sleep(Duration::from_millis(60));
+        assert!(throttle.retry_after(ip).is_none());
+        throttle.record_failure(ip);
+        assert_eq!(throttle.failures.lock().unwrap().len(), 1);
+    }
+}
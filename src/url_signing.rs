@@ -0,0 +1,104 @@
+use constant_time_eq::constant_time_eq;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const EXPIRES_PARAM: &str = "expires";
+const SIGNATURE_PARAM: &str = "sig";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Signs URLs so they can be used to see private paths until they expire
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub fn new(key: &[u8]) -> Self {
+        UrlSigner { key: key.to_vec() }
+    }
+
+    /// The hostname is included, so a URL for one site can't be used for another
+    fn get_signature(&self, hostname: &str, path: &str, expires: u64) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\n{}\n{}", hostname, path, expires).as_bytes());
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    /// The query string granting access to `path` until `expires` (in seconds since the Unix
+    /// epoch)
+    pub fn sign(&self, hostname: &str, path: &str, expires: u64) -> String {
+        format!(
+            "{}={}&{}={}",
+            EXPIRES_PARAM,
+            expires,
+            SIGNATURE_PARAM,
+            self.get_signature(hostname, path, expires)
+        )
+    }
+
+    /// Whether a request's query string has a valid, unexpired signature for its path
+    pub fn verify(&self, hostname: &str, path: &str, query: &str) -> bool {
+        let mut expires = None;
+        let mut signature = None;
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                EXPIRES_PARAM => expires = value.parse::<u64>().ok(),
+                SIGNATURE_PARAM => signature = Some(value),
+                _ => {}
+            }
+        }
+
+        match (expires, signature) {
+            (Some(expires), Some(signature)) => {
+                expires >= now()
+                    && constant_time_eq(
+                        self.get_signature(hostname, path, expires).as_bytes(),
+                        signature.as_bytes(),
+                    )
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = UrlSigner::new(b"secret");
+        let expires = now() + 60;
+        let query = signer.sign("example.com", "/reports/q3.pdf", expires);
+        assert!(query.starts_with(&format!("expires={}&sig=", expires)));
+
+        assert!(signer.verify("example.com", "/reports/q3.pdf", &query));
+        assert!(signer.verify(
+            "example.com",
+            "/reports/q3.pdf",
+            &format!("download=1&{}", query)
+        ));
+        assert!(!signer.verify("example.org", "/reports/q3.pdf", &query));
+        assert!(!signer.verify("example.com", "/reports/q4.pdf", &query));
+        assert!(!UrlSigner::new(b"other").verify("example.com", "/reports/q3.pdf", &query));
+        assert!(!signer.verify("example.com", "/reports/q3.pdf", ""));
+
+        let tampered = query.replace(&expires.to_string(), &(expires + 3600).to_string());
+        assert!(!signer.verify("example.com", "/reports/q3.pdf", &tampered));
+    }
+
+    #[test]
+    fn test_expired() {
+        let signer = UrlSigner::new(b"secret");
+        let query = signer.sign("example.com", "/report.pdf", now() - 1);
+        assert!(!signer.verify("example.com", "/report.pdf", &query));
+    }
+}
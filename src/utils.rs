@@ -1,3 +1,4 @@
+use crate::rate_limit::RateLimit;
 use log::error;
 use std::env;
 use std::process::exit;
@@ -29,6 +30,120 @@ pub fn get_workers() -> usize {
         .expect("Invalid worker count")
 }
 
+pub fn get_retry_attempts() -> u8 {
+    get_env_or_default("RETRY_ATTEMPTS", Some("4"))
+        .parse::<u8>()
+        .expect("Invalid retry attempts")
+}
+
+pub fn get_rescan_interval() -> u64 {
+    get_env_or_default("RESCAN_INTERVAL", Some("60"))
+        .parse::<u64>()
+        .expect("Invalid rescan interval")
+}
+
+pub fn get_auth_max_failures() -> u32 {
+    get_env_or_default("AUTH_MAX_FAILURES", Some("10"))
+        .parse::<u32>()
+        .expect("Invalid auth max failures")
+}
+
+pub fn get_auth_failure_window() -> u64 {
+    get_env_or_default("AUTH_FAILURE_WINDOW", Some("300"))
+        .parse::<u64>()
+        .expect("Invalid auth failure window")
+}
+
+pub fn get_release_retention() -> usize {
+    get_env_or_default("RELEASE_RETENTION", Some("10"))
+        .parse::<usize>()
+        .expect("Invalid release retention")
+}
+
+pub fn get_ready_max_config_errors() -> usize {
+    get_env_or_default("READY_MAX_CONFIG_ERRORS", Some("10"))
+        .parse::<usize>()
+        .expect("Invalid ready max config errors")
+}
+
+pub fn get_deploy_max_entries() -> usize {
+    get_env_or_default("DEPLOY_MAX_ENTRIES", Some("100000"))
+        .parse::<usize>()
+        .expect("Invalid deploy max entries")
+}
+
+/// Fraction of successful requests to write to the access log
+pub fn get_access_log_sample_rate() -> f64 {
+    let sample_rate = get_env_or_default("ACCESS_LOG_SAMPLE_RATE", Some("1"))
+        .parse::<f64>()
+        .expect("Invalid access log sample rate");
+    if !(0.0..=1.0).contains(&sample_rate) {
+        log_error_and_quit("ACCESS_LOG_SAMPLE_RATE must be between 0 and 1.");
+    }
+    sample_rate
+}
+
+/// The default rate limit for sites, if there is one. The burst defaults to a second's worth.
+pub fn get_rate_limit() -> Option<RateLimit> {
+    let rate = env::var("RATE_LIMIT")
+        .ok()?
+        .parse::<f64>()
+        .expect("Invalid rate limit");
+    let rate_limit = RateLimit {
+        rate,
+        burst: get_env_or_default("RATE_LIMIT_BURST", Some(&rate.ceil().to_string()))
+            .parse::<u32>()
+            .expect("Invalid rate limit burst"),
+    };
+    if let Err(e) = rate_limit.validate() {
+        log_error_and_quit(&e);
+    }
+    Some(rate_limit)
+}
+
+/// A size given in megabytes, in bytes
+pub fn get_size_mb(var_name: &str, default: &str) -> u64 {
+    get_env_or_default(var_name, Some(default))
+        .parse::<u64>()
+        .unwrap_or_else(|_| log_error_and_quit(&format!("Invalid size for {}.", var_name)))
+        * 1024
+        * 1024
+}
+
+/// The Kubernetes service for traefik-pages, as `name:port`
+pub fn get_kubernetes_service() -> (String, u16) {
+    let service = get_env_or_default("KUBERNETES_SERVICE", Some("traefik-pages:5000"));
+    match service.rsplit_once(':') {
+        Some((name, port)) => (
+            name.to_owned(),
+            port.parse::<u16>()
+                .expect("Invalid Kubernetes service port"),
+        ),
+        None => log_error_and_quit("KUBERNETES_SERVICE must be in the format name:port."),
+    }
+}
+
+pub fn get_env_list(var_name: &str) -> Vec<String> {
+    get_env_or_default(var_name, Some(""))
+        .split(',')
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// URLs other reverse proxies can reach traefik-pages on, defaulting to the Traefik service
+/// URLs, then to the local port
+pub fn get_upstream_urls(traefik_service_urls: &[String]) -> Vec<String> {
+    let upstream_urls = get_env_list("UPSTREAM_URLS");
+    if !upstream_urls.is_empty() {
+        return upstream_urls;
+    }
+    if !traefik_service_urls.is_empty() {
+        return traefik_service_urls.to_vec();
+    }
+    vec![format!("http://127.0.0.1:{}", get_port())]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -48,4 +163,95 @@ mod tests {
         set_var("PORT", "8000");
         assert_eq!(get_port(), 8000);
     }
+
+    #[test]
+    fn test_get_retry_attempts() {
+        assert_eq!(get_retry_attempts(), 4);
+        set_var("RETRY_ATTEMPTS", "2");
+        assert_eq!(get_retry_attempts(), 2);
+    }
+
+    #[test]
+    fn test_get_auth_max_failures() {
+        assert_eq!(get_auth_max_failures(), 10);
+        set_var("AUTH_MAX_FAILURES", "5");
+        assert_eq!(get_auth_max_failures(), 5);
+    }
+
+    #[test]
+    fn test_get_release_retention() {
+        assert_eq!(get_release_retention(), 10);
+        set_var("RELEASE_RETENTION", "3");
+        assert_eq!(get_release_retention(), 3);
+    }
+
+    #[test]
+    fn test_get_ready_max_config_errors() {
+        assert_eq!(get_ready_max_config_errors(), 10);
+        set_var("READY_MAX_CONFIG_ERRORS", "0");
+        assert_eq!(get_ready_max_config_errors(), 0);
+    }
+
+    #[test]
+    fn test_get_access_log_sample_rate() {
+        assert_eq!(get_access_log_sample_rate(), 1.0);
+        set_var("ACCESS_LOG_SAMPLE_RATE", "0.1");
+        assert_eq!(get_access_log_sample_rate(), 0.1);
+    }
+
+    #[test]
+    fn test_get_rate_limit() {
+        assert_eq!(get_rate_limit(), None);
+        set_var("RATE_LIMIT", "2.5");
+        assert_eq!(
+            get_rate_limit(),
+            Some(RateLimit {
+                rate: 2.5,
+                burst: 3
+            })
+        );
+        set_var("RATE_LIMIT_BURST", "10");
+        assert_eq!(get_rate_limit().unwrap().burst, 10);
+    }
+
+    #[test]
+    fn test_get_size_mb() {
+        assert_eq!(get_size_mb("TEST_SIZE_MB", "2"), 2 * 1024 * 1024);
+        set_var("TEST_SIZE_MB", "5");
+        assert_eq!(get_size_mb("TEST_SIZE_MB", "2"), 5 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_get_kubernetes_service() {
+        assert_eq!(
+            get_kubernetes_service(),
+            (String::from("traefik-pages"), 5000)
+        );
+        set_var("KUBERNETES_SERVICE", "pages:8080");
+        assert_eq!(get_kubernetes_service(), (String::from("pages"), 8080));
+    }
+
+    #[test]
+    fn test_get_env_list() {
+        assert!(get_env_list("TEST_ENV_LIST").is_empty());
+        set_var("TEST_ENV_LIST", "compress, headers@file,,");
+        assert_eq!(
+            get_env_list("TEST_ENV_LIST"),
+            vec![String::from("compress"), String::from("headers@file")]
+        );
+    }
+
+    #[test]
+    fn test_get_upstream_urls() {
+        let traefik_service_urls = vec![String::from("http://10.0.0.1:5000")];
+        assert_eq!(
+            get_upstream_urls(&traefik_service_urls),
+            traefik_service_urls
+        );
+        set_var("UPSTREAM_URLS", "http://pages.internal");
+        assert_eq!(
+            get_upstream_urls(&traefik_service_urls),
+            vec![String::from("http://pages.internal")]
+        );
+    }
 }
@@ -0,0 +1,210 @@
+use crate::site::is_valid_hostname;
+use serde_derive::Serialize;
+use serde_json::{Map, Value};
+
+/// A problem found in the generated provider configuration
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub name: String,
+    pub problem: String,
+}
+
+impl Diagnostic {
+    pub fn new(name: &str, problem: impl Into<String>) -> Self {
+        Diagnostic {
+            name: name.to_owned(),
+            problem: problem.into(),
+        }
+    }
+}
+
+/// References to other providers (`name@provider`) can't be checked from here
+fn is_local_reference(name: &str) -> bool {
+    !name.contains('@')
+}
+
+fn is_balanced(rule: &str) -> bool {
+    let mut depth: i32 = 0;
+    let mut in_literal = false;
+    for c in rule.chars() {
+        match c {
+            '`' => in_literal = !in_literal,
+            '(' if !in_literal => depth += 1,
+            ')' if !in_literal => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+    }
+    depth == 0 && !in_literal
+}
+
+fn validate_rule(rule: &str) -> Result<(), String> {
+    if !is_balanced(rule) {
+        return Err(format!("Unbalanced rule {}", rule));
+    }
+
+    let mut hosts = rule.split("Host(`").skip(1).peekable();
+    if hosts.peek().is_none() {
+        return Err(format!("Rule has no host {}", rule));
+    }
+    for host in hosts {
+        let hostname = host.split('`').next().unwrap_or_default();
+        if !is_valid_hostname(hostname) {
+            return Err(format!("Invalid hostname {} in rule", hostname));
+        }
+    }
+    Ok(())
+}
+
+fn get_names(config: &Value, section: &str) -> Vec<String> {
+    config["http"][section]
+        .as_object()
+        .map(|o| o.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+fn get_references(value: &Value) -> Vec<&str> {
+    value
+        .as_array()
+        .map(|a| a.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+/// Check a generated provider config, removing routers Traefik would reject
+pub fn validate_provider_config(config: &mut Value) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    let middlewares = get_names(config, "middlewares");
+    let services = get_names(config, "services");
+
+    if let Some(middleware_definitions) = config["http"]["middlewares"].as_object() {
+        for (name, middleware) in middleware_definitions.iter() {
+            for reference in get_references(&middleware["chain"]["middlewares"]) {
+                if is_local_reference(reference) && !middlewares.iter().any(|m| m == reference) {
+                    diagnostics.push(Diagnostic::new(
+                        name,
+                        format!("Unknown middleware {}", reference),
+                    ));
+                }
+            }
+        }
+    }
+
+    let routers = match config["http"]["routers"].as_object_mut() {
+        Some(r) => r,
+        None => return diagnostics,
+    };
+
+    let mut valid_routers = Map::new();
+    for (name, router) in routers.iter() {
+        if let Err(problem) = validate_rule(router["rule"].as_str().unwrap_or_default()) {
+            diagnostics.push(Diagnostic::new(name, problem));
+            continue;
+        }
+
+        if let Some(service) = router["service"].as_str() {
+            if is_local_reference(service) && !services.iter().any(|s| s == service) {
+                diagnostics.push(Diagnostic::new(
+                    name,
+                    format!("Unknown service {}", service),
+                ));
+            }
+        }
+
+        for reference in get_references(&router["middlewares"]) {
+            if is_local_reference(reference) && !middlewares.iter().any(|m| m == reference) {
+                diagnostics.push(Diagnostic::new(
+                    name,
+                    format!("Unknown middleware {}", reference),
+                ));
+            }
+        }
+
+        valid_routers.insert(name.clone(), router.clone());
+    }
+    *routers = valid_routers;
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_rule() {
+        assert!(validate_rule("Host(`example.com`)").is_ok());
+        assert!(
+            validate_rule("Host(`example.com`) && (Path(`/docs`) || PathPrefix(`/docs/`))").is_ok()
+        );
+        assert!(validate_rule("Host(`example.com`) && PathPrefix(`/(`)").is_ok());
+
+        assert!(validate_rule("Host(`example.com`").is_err());
+        assert!(validate_rule("Host(`example.com)").is_err());
+        assert!(validate_rule("Host(`.example.com`)").is_err());
+        assert!(validate_rule("PathPrefix(`/`)").is_err());
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let mut config = json!({
+            "http": {
+                "routers": {
+                    "example-com": {
+                        "rule": "Host(`example.com`)",
+                        "service": "traefik-pages",
+                        "middlewares": ["tp-default", "compress@file"]
+                    }
+                },
+                "middlewares": {
+                    "tp-default": {"chain": {"middlewares": ["tp-retry", "headers@file"]}},
+                    "tp-retry": {"retry": {"attempts": 4}}
+                },
+                "services": {
+                    "traefik-pages": {}
+                }
+            }
+        });
+        let original = config.clone();
+        assert!(validate_provider_config(&mut config).is_empty());
+        assert_eq!(config, original);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let mut config = json!({
+            "http": {
+                "routers": {
+                    "example-com": {
+                        "rule": "Host(`example.com`)",
+                        "service": "traefik-pages",
+                        "middlewares": ["tp-default"]
+                    },
+                    "broken": {
+                        "rule": "Host(`example.org`",
+                        "service": "traefik-pages@docker"
+                    }
+                },
+                "middlewares": {
+                    "tp-default": {"chain": {"middlewares": ["compress"]}}
+                }
+            }
+        });
+        assert_eq!(
+            validate_provider_config(&mut config),
+            vec![
+                Diagnostic::new("tp-default", "Unknown middleware compress"),
+                Diagnostic::new("broken", "Unbalanced rule Host(`example.org`"),
+                Diagnostic::new("example-com", "Unknown service traefik-pages"),
+            ]
+        );
+        assert!(config["http"]["routers"].get("broken").is_none());
+        assert!(config["http"]["routers"].get("example-com").is_some());
+    }
+}